@@ -0,0 +1,7 @@
+-- Add down migration script here
+DROP INDEX group_messages_keyset_pag_idx;
+DROP TABLE group_messages;
+DROP INDEX group_members_user_idx;
+DROP TABLE group_members;
+DROP TABLE groups;
+DROP TYPE group_role;
\ No newline at end of file
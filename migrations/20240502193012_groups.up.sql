@@ -0,0 +1,31 @@
+-- Add up migration script here
+CREATE TYPE group_role AS ENUM ('owner', 'admin', 'member');
+
+CREATE TABLE groups (
+    id serial PRIMARY KEY,
+    name varchar(64) NOT NULL CHECK (length(name) > 0),
+    created_at timestamp DEFAULT now() NOT NULL
+);
+
+CREATE TABLE group_members (
+    group_id integer REFERENCES groups(id) ON DELETE CASCADE NOT NULL,
+    user_id uuid REFERENCES users(id) NOT NULL,
+    role group_role DEFAULT 'member' NOT NULL,
+    invited_by uuid REFERENCES users(id),
+    public_key bytea CHECK (length(public_key) = 32),
+    created_at timestamp DEFAULT now() NOT NULL,
+    PRIMARY KEY (group_id, user_id)
+);
+
+CREATE INDEX group_members_user_idx ON group_members(user_id);
+
+CREATE TABLE group_messages (
+    id serial PRIMARY KEY,
+    group_id integer REFERENCES groups(id) ON DELETE CASCADE NOT NULL,
+    sender_id uuid REFERENCES users(id) NOT NULL,
+    content bytea NOT NULL,
+    created_at timestamp DEFAULT now() NOT NULL
+);
+
+CREATE INDEX group_messages_keyset_pag_idx ON group_messages
+USING btree (group_id, created_at DESC, id DESC);
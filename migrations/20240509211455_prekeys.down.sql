@@ -0,0 +1,5 @@
+-- Add down migration script here
+DROP INDEX one_time_prekeys_key_id_idx;
+DROP TABLE one_time_prekeys;
+DROP TABLE signed_prekeys;
+DROP TABLE identity_keys;
\ No newline at end of file
@@ -0,0 +1,24 @@
+-- Add up migration script here
+CREATE TABLE identity_keys (
+    user_id uuid PRIMARY KEY REFERENCES users(id),
+    public_key bytea NOT NULL CHECK (length(public_key) = 32),
+    created_at timestamp DEFAULT now() NOT NULL
+);
+
+CREATE TABLE signed_prekeys (
+    user_id uuid PRIMARY KEY REFERENCES identity_keys(user_id) ON DELETE CASCADE,
+    key_id integer NOT NULL,
+    public_key bytea NOT NULL CHECK (length(public_key) = 32),
+    signature bytea NOT NULL CHECK (length(signature) = 64),
+    created_at timestamp DEFAULT now() NOT NULL
+);
+
+CREATE TABLE one_time_prekeys (
+    id serial PRIMARY KEY,
+    user_id uuid REFERENCES identity_keys(user_id) ON DELETE CASCADE NOT NULL,
+    key_id integer NOT NULL,
+    public_key bytea NOT NULL CHECK (length(public_key) = 32),
+    created_at timestamp DEFAULT now() NOT NULL
+);
+
+CREATE UNIQUE INDEX one_time_prekeys_key_id_idx ON one_time_prekeys(user_id, key_id);
@@ -0,0 +1,6 @@
+-- Add down migration script here
+ALTER TABLE one_time_prekeys DROP COLUMN signature;
+ALTER TABLE group_members DROP COLUMN key_signature;
+ALTER TABLE chats
+DROP COLUMN recipient_key_signature,
+DROP COLUMN sender_key_signature;
\ No newline at end of file
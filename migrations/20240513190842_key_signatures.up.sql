@@ -0,0 +1,14 @@
+-- Add up migration script here
+ALTER TABLE chats
+ADD COLUMN sender_key_signature bytea CHECK (length(sender_key_signature) = 64),
+ADD COLUMN recipient_key_signature bytea CHECK (length(recipient_key_signature) = 64);
+
+ALTER TABLE group_members
+ADD COLUMN key_signature bytea CHECK (length(key_signature) = 64);
+
+-- Unsigned one-time prekeys can't be trusted, so clients have to upload
+-- a fresh batch.
+DELETE FROM one_time_prekeys;
+
+ALTER TABLE one_time_prekeys
+ADD COLUMN signature bytea NOT NULL CHECK (length(signature) = 64);
@@ -0,0 +1,4 @@
+-- Add down migration script here
+ALTER TABLE messages DROP COLUMN key_version;
+DROP TABLE chat_keys;
+ALTER TABLE chats DROP COLUMN key_version;
\ No newline at end of file
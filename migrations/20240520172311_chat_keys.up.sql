@@ -0,0 +1,27 @@
+-- Add up migration script here
+ALTER TABLE chats ADD COLUMN key_version integer DEFAULT 1 NOT NULL;
+
+CREATE TABLE chat_keys (
+    chat_id integer REFERENCES chats(id) ON DELETE CASCADE NOT NULL,
+    version integer NOT NULL,
+    sender_public_key bytea NOT NULL CHECK (length(sender_public_key) = 32),
+    sender_key_signature bytea CHECK (length(sender_key_signature) = 64),
+    recipient_public_key bytea NOT NULL CHECK (length(recipient_public_key) = 32),
+    recipient_key_signature bytea CHECK (length(recipient_key_signature) = 64),
+    created_at timestamp DEFAULT now() NOT NULL,
+    PRIMARY KEY (chat_id, version)
+);
+
+INSERT INTO chat_keys (
+    chat_id,
+    version,
+    sender_public_key,
+    sender_key_signature,
+    recipient_public_key,
+    recipient_key_signature
+)
+SELECT id, key_version, sender_public_key, sender_key_signature, recipient_public_key, recipient_key_signature
+FROM chats
+WHERE recipient_public_key IS NOT NULL;
+
+ALTER TABLE messages ADD COLUMN key_version integer DEFAULT 1 NOT NULL;
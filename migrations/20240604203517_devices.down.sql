@@ -0,0 +1,7 @@
+-- Add down migration script here
+DROP TABLE message_envelopes;
+DROP TABLE chat_device_keys;
+DROP INDEX sessions_device_idx;
+ALTER TABLE sessions DROP COLUMN device_id;
+DROP INDEX devices_user_idx;
+DROP TABLE devices;
\ No newline at end of file
@@ -0,0 +1,44 @@
+-- Add up migration script here
+CREATE TABLE devices (
+    id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id uuid REFERENCES users(id) NOT NULL,
+    name varchar(64) NOT NULL,
+    created_at timestamp DEFAULT now() NOT NULL,
+    last_seen_at timestamp DEFAULT now() NOT NULL
+);
+
+CREATE INDEX devices_user_idx ON devices(user_id);
+
+-- Sessions from before devices existed each get a legacy device of their
+-- own, so nobody is signed out by the upgrade.
+ALTER TABLE sessions
+ADD COLUMN device_id uuid;
+
+UPDATE sessions SET device_id = gen_random_uuid();
+
+INSERT INTO devices (id, user_id, name, created_at, last_seen_at)
+SELECT device_id, user_id, 'legacy', created_at, created_at
+FROM sessions;
+
+ALTER TABLE sessions
+ADD CONSTRAINT sessions_device_id_fkey
+    FOREIGN KEY (device_id) REFERENCES devices(id) ON DELETE CASCADE,
+ALTER COLUMN device_id SET NOT NULL;
+
+CREATE UNIQUE INDEX sessions_device_idx ON sessions(device_id);
+
+CREATE TABLE chat_device_keys (
+    chat_id integer REFERENCES chats(id) ON DELETE CASCADE NOT NULL,
+    device_id uuid REFERENCES devices(id) ON DELETE CASCADE NOT NULL,
+    public_key bytea NOT NULL CHECK (length(public_key) = 32),
+    signature bytea NOT NULL CHECK (length(signature) = 64),
+    created_at timestamp DEFAULT now() NOT NULL,
+    PRIMARY KEY (chat_id, device_id)
+);
+
+CREATE TABLE message_envelopes (
+    message_id integer REFERENCES messages(id) ON DELETE CASCADE NOT NULL,
+    device_id uuid REFERENCES devices(id) ON DELETE CASCADE NOT NULL,
+    content bytea NOT NULL,
+    PRIMARY KEY (message_id, device_id)
+);
@@ -0,0 +1,5 @@
+-- Add down migration script here
+ALTER TABLE messages
+DROP COLUMN deleted_at,
+DROP COLUMN edited_at,
+DROP COLUMN edit_count;
\ No newline at end of file
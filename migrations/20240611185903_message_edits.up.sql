@@ -0,0 +1,5 @@
+-- Add up migration script here
+ALTER TABLE messages
+ADD COLUMN edit_count integer DEFAULT 0 NOT NULL,
+ADD COLUMN edited_at timestamp,
+ADD COLUMN deleted_at timestamp;
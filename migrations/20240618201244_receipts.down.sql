@@ -0,0 +1,5 @@
+-- Add down migration script here
+DROP FUNCTION is_read;
+DROP FUNCTION is_delivered;
+DROP TABLE chat_receipts;
+ALTER TABLE messages DROP COLUMN chat_id;
\ No newline at end of file
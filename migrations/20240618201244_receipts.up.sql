@@ -0,0 +1,36 @@
+-- Add up migration script here
+ALTER TABLE messages ADD COLUMN chat_id integer REFERENCES chats(id) ON DELETE CASCADE;
+
+UPDATE messages m
+SET chat_id = c.id
+FROM chats c
+WHERE least(c.sender_id, c.recipient_id) = least(m.sender_id, m.recipient_id)
+    AND greatest(c.sender_id, c.recipient_id) = greatest(m.sender_id, m.recipient_id);
+
+ALTER TABLE messages ALTER COLUMN chat_id SET NOT NULL;
+
+-- Each participant marks how far into the chat they have received and read.
+CREATE TABLE chat_receipts (
+    chat_id integer REFERENCES chats(id) ON DELETE CASCADE NOT NULL,
+    user_id uuid REFERENCES users(id) NOT NULL,
+    delivered_up_to integer DEFAULT 0 NOT NULL,
+    read_up_to integer DEFAULT 0 NOT NULL CHECK (read_up_to <= delivered_up_to),
+    updated_at timestamp DEFAULT now() NOT NULL,
+    PRIMARY KEY (chat_id, user_id)
+);
+
+CREATE FUNCTION is_delivered(m messages) RETURNS boolean
+LANGUAGE sql STABLE AS $$
+    SELECT EXISTS(
+        SELECT 1 FROM chat_receipts
+        WHERE chat_id = m.chat_id AND user_id = m.recipient_id AND delivered_up_to >= m.id
+    );
+$$;
+
+CREATE FUNCTION is_read(m messages) RETURNS boolean
+LANGUAGE sql STABLE AS $$
+    SELECT EXISTS(
+        SELECT 1 FROM chat_receipts
+        WHERE chat_id = m.chat_id AND user_id = m.recipient_id AND read_up_to >= m.id
+    );
+$$;
@@ -0,0 +1,4 @@
+-- Add down migration script here
+ALTER TABLE messages DROP COLUMN attachment_id;
+
+DROP TABLE attachments;
\ No newline at end of file
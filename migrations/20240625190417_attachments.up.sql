@@ -0,0 +1,10 @@
+-- Add up migration script here
+CREATE TABLE attachments (
+    id uuid PRIMARY KEY,
+    chat_id integer REFERENCES chats(id) ON DELETE CASCADE NOT NULL,
+    uploader_id uuid REFERENCES users(id) NOT NULL,
+    size bigint NOT NULL,
+    created_at timestamp DEFAULT now() NOT NULL
+);
+
+ALTER TABLE messages ADD COLUMN attachment_id uuid REFERENCES attachments(id) ON DELETE SET NULL;
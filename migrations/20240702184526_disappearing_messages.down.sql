@@ -0,0 +1,10 @@
+-- Add down migration script here
+DROP INDEX messages_expires_at_idx;
+
+ALTER TABLE messages
+DROP COLUMN expires_at,
+DROP COLUMN ttl_sec;
+
+ALTER TABLE chats
+DROP COLUMN ttl_after_read,
+DROP COLUMN message_ttl_sec;
\ No newline at end of file
@@ -0,0 +1,12 @@
+-- Add up migration script here
+ALTER TABLE chats
+ADD COLUMN message_ttl_sec integer CHECK (message_ttl_sec > 0),
+ADD COLUMN ttl_after_read boolean DEFAULT false NOT NULL;
+
+-- The chat's TTL is copied at send time. `expires_at` is set right away, or
+-- once the recipient reads the message when the chat counts from reading.
+ALTER TABLE messages
+ADD COLUMN ttl_sec integer,
+ADD COLUMN expires_at timestamp;
+
+CREATE INDEX messages_expires_at_idx ON messages (expires_at) WHERE expires_at IS NOT NULL;
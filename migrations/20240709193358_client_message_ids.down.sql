@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP INDEX messages_client_id_idx;
+
+ALTER TABLE messages DROP COLUMN client_id;
\ No newline at end of file
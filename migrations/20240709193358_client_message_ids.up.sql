@@ -0,0 +1,4 @@
+-- Add up migration script here
+ALTER TABLE messages ADD COLUMN client_id uuid;
+
+CREATE UNIQUE INDEX messages_client_id_idx ON messages (sender_id, client_id);
@@ -0,0 +1,6 @@
+-- Add down migration script here
+DROP INDEX messages_seq_idx;
+
+ALTER TABLE messages DROP COLUMN seq;
+
+ALTER TABLE chats DROP COLUMN last_seq;
\ No newline at end of file
@@ -0,0 +1,21 @@
+-- Add up migration script here
+ALTER TABLE chats ADD COLUMN last_seq integer DEFAULT 0 NOT NULL;
+
+ALTER TABLE messages ADD COLUMN seq integer;
+
+UPDATE messages m
+SET seq = s.seq
+FROM (
+    SELECT id, row_number() OVER (PARTITION BY chat_id ORDER BY id) AS seq
+    FROM messages
+) s
+WHERE s.id = m.id;
+
+UPDATE chats c
+SET last_seq = s.last_seq
+FROM (SELECT chat_id, max(seq) AS last_seq FROM messages GROUP BY chat_id) s
+WHERE s.chat_id = c.id;
+
+ALTER TABLE messages ALTER COLUMN seq SET NOT NULL;
+
+CREATE UNIQUE INDEX messages_seq_idx ON messages (chat_id, seq);
@@ -0,0 +1,9 @@
+-- Add up migration script here
+CREATE TABLE message_reactions (
+    message_id integer REFERENCES messages(id) ON DELETE CASCADE NOT NULL,
+    user_id uuid REFERENCES users(id) NOT NULL,
+    reaction_key bytea NOT NULL CHECK (length(reaction_key) BETWEEN 1 AND 32),
+    payload bytea NOT NULL CHECK (length(payload) <= 256),
+    created_at timestamp DEFAULT now() NOT NULL,
+    PRIMARY KEY (message_id, user_id, reaction_key)
+);
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP INDEX messages_reply_to_idx;
+
+ALTER TABLE messages DROP COLUMN reply_to;
\ No newline at end of file
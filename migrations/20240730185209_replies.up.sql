@@ -0,0 +1,5 @@
+-- Add up migration script here
+ALTER TABLE messages ADD COLUMN reply_to integer REFERENCES messages(id) ON DELETE SET NULL;
+
+CREATE INDEX messages_reply_to_idx ON messages (reply_to, created_at DESC, id DESC)
+WHERE reply_to IS NOT NULL;
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP FUNCTION is_blocked;
+
+DROP TABLE blocks;
\ No newline at end of file
@@ -0,0 +1,16 @@
+-- Add up migration script here
+CREATE TABLE blocks (
+    blocker_id uuid REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+    blocked_id uuid REFERENCES users(id) ON DELETE CASCADE NOT NULL CHECK (blocked_id <> blocker_id),
+    created_at timestamp DEFAULT now() NOT NULL,
+    PRIMARY KEY (blocker_id, blocked_id)
+);
+
+-- A block works both ways, whoever placed it.
+CREATE FUNCTION is_blocked(a uuid, b uuid) RETURNS boolean
+LANGUAGE sql STABLE AS $$
+    SELECT EXISTS(
+        SELECT 1 FROM blocks
+        WHERE (blocker_id = a AND blocked_id = b) OR (blocker_id = b AND blocked_id = a)
+    );
+$$;
@@ -0,0 +1,8 @@
+-- Add down migration script here
+DROP INDEX sessions_user_idx;
+
+ALTER TABLE sessions
+DROP COLUMN revoked_at,
+DROP COLUMN ip,
+DROP COLUMN user_agent,
+DROP COLUMN refreshed_at;
\ No newline at end of file
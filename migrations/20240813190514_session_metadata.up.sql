@@ -0,0 +1,10 @@
+-- Add up migration script here
+ALTER TABLE sessions
+ADD COLUMN refreshed_at timestamp DEFAULT now() NOT NULL,
+ADD COLUMN user_agent varchar(256),
+ADD COLUMN ip varchar(45),
+-- Revoked sessions keep their token so refreshing with it isn't taken for
+-- token reuse.
+ADD COLUMN revoked_at timestamp;
+
+CREATE INDEX sessions_user_idx ON sessions(user_id);
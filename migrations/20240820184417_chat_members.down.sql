@@ -0,0 +1,10 @@
+-- Add down migration script here
+DROP INDEX messages_keyset_pag_idx;
+
+CREATE INDEX messages_keyset_pag_idx ON messages
+USING btree (sender_id, recipient_id, created_at DESC, id DESC);
+
+DROP INDEX chats_recipient_idx;
+DROP INDEX chats_sender_idx;
+
+DROP VIEW chat_members;
\ No newline at end of file
@@ -0,0 +1,25 @@
+-- Add up migration script here
+-- Every chat once from each side, so who sent the invite never decides
+-- who may act on it.
+CREATE VIEW chat_members AS
+SELECT
+    id AS chat_id,
+    sender_id AS user_id,
+    recipient_id AS friend_id,
+    recipient_public_key IS NOT NULL AS accepted
+FROM chats
+UNION ALL
+SELECT
+    id,
+    recipient_id,
+    sender_id,
+    recipient_public_key IS NOT NULL
+FROM chats;
+
+CREATE INDEX chats_sender_idx ON chats(sender_id);
+CREATE INDEX chats_recipient_idx ON chats(recipient_id);
+
+DROP INDEX messages_keyset_pag_idx;
+
+CREATE INDEX messages_keyset_pag_idx ON messages
+USING btree (chat_id, created_at DESC, id DESC);
@@ -0,0 +1,7 @@
+-- Add down migration script here
+DROP TABLE signin_challenges;
+
+DROP INDEX recovery_codes_user_hash_idx;
+DROP TABLE recovery_codes;
+
+DROP TABLE totp_secrets;
\ No newline at end of file
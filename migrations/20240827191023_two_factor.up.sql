@@ -0,0 +1,30 @@
+-- Add up migration script here
+CREATE TABLE totp_secrets (
+    user_id uuid PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    secret bytea NOT NULL CHECK (length(secret) = 20),
+    -- Null until the user proves their authenticator holds the secret.
+    confirmed_at timestamp,
+    -- Time step of the last code accepted, so no code works twice.
+    last_used_step bigint,
+    created_at timestamp DEFAULT now() NOT NULL
+);
+
+CREATE TABLE recovery_codes (
+    id serial PRIMARY KEY,
+    user_id uuid REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+    code_hash bytea NOT NULL CHECK (length(code_hash) = 32),
+    used_at timestamp,
+    created_at timestamp DEFAULT now() NOT NULL
+);
+
+CREATE UNIQUE INDEX recovery_codes_user_hash_idx ON recovery_codes(user_id, code_hash);
+
+-- Signins that got the password right and still owe a second factor.
+CREATE TABLE signin_challenges (
+    token varchar(64) PRIMARY KEY,
+    user_id uuid REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+    device_id uuid REFERENCES devices(id) ON DELETE CASCADE,
+    device_name varchar(64),
+    attempts integer DEFAULT 0 NOT NULL,
+    expires_at timestamp NOT NULL
+);
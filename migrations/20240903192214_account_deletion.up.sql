@@ -0,0 +1,80 @@
+-- Add up migration script here
+ALTER TABLE users
+ADD COLUMN deletion_scheduled_at timestamp,
+ADD COLUMN deleted_at timestamp;
+
+CREATE INDEX users_deletion_scheduled_idx ON users(deletion_scheduled_at)
+WHERE deletion_scheduled_at IS NOT NULL;
+
+ALTER TABLE sessions
+DROP CONSTRAINT sessions_user_id_fkey,
+ADD CONSTRAINT sessions_user_id_fkey
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE;
+
+ALTER TABLE chats
+DROP CONSTRAINT chats_sender_id_fkey,
+DROP CONSTRAINT chats_recipient_id_fkey,
+ADD CONSTRAINT chats_sender_id_fkey
+    FOREIGN KEY (sender_id) REFERENCES users(id) ON DELETE CASCADE,
+ADD CONSTRAINT chats_recipient_id_fkey
+    FOREIGN KEY (recipient_id) REFERENCES users(id) ON DELETE CASCADE;
+
+ALTER TABLE messages
+DROP CONSTRAINT messages_sender_id_fkey,
+DROP CONSTRAINT messages_recipient_id_fkey,
+ADD CONSTRAINT messages_sender_id_fkey
+    FOREIGN KEY (sender_id) REFERENCES users(id) ON DELETE CASCADE,
+ADD CONSTRAINT messages_recipient_id_fkey
+    FOREIGN KEY (recipient_id) REFERENCES users(id) ON DELETE CASCADE;
+
+ALTER TABLE group_members
+DROP CONSTRAINT group_members_user_id_fkey,
+DROP CONSTRAINT group_members_invited_by_fkey,
+ADD CONSTRAINT group_members_user_id_fkey
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+ADD CONSTRAINT group_members_invited_by_fkey
+    FOREIGN KEY (invited_by) REFERENCES users(id) ON DELETE SET NULL;
+
+ALTER TABLE group_messages
+DROP CONSTRAINT group_messages_sender_id_fkey,
+ADD CONSTRAINT group_messages_sender_id_fkey
+    FOREIGN KEY (sender_id) REFERENCES users(id) ON DELETE CASCADE;
+
+ALTER TABLE identity_keys
+DROP CONSTRAINT identity_keys_user_id_fkey,
+ADD CONSTRAINT identity_keys_user_id_fkey
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE;
+
+ALTER TABLE devices
+DROP CONSTRAINT devices_user_id_fkey,
+ADD CONSTRAINT devices_user_id_fkey
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE;
+
+ALTER TABLE chat_receipts
+DROP CONSTRAINT chat_receipts_user_id_fkey,
+ADD CONSTRAINT chat_receipts_user_id_fkey
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE;
+
+ALTER TABLE attachments
+DROP CONSTRAINT attachments_uploader_id_fkey,
+ADD CONSTRAINT attachments_uploader_id_fkey
+    FOREIGN KEY (uploader_id) REFERENCES users(id) ON DELETE CASCADE;
+
+ALTER TABLE message_reactions
+DROP CONSTRAINT message_reactions_user_id_fkey,
+ADD CONSTRAINT message_reactions_user_id_fkey
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE;
+
+-- A block works both ways, whoever placed it. A deleted account, kept
+-- around only to attribute its pseudonymized messages, counts as blocked
+-- by everyone.
+CREATE OR REPLACE FUNCTION is_blocked(a uuid, b uuid) RETURNS boolean
+LANGUAGE sql STABLE AS $$
+    SELECT EXISTS(
+        SELECT 1 FROM blocks
+        WHERE (blocker_id = a AND blocked_id = b) OR (blocker_id = b AND blocked_id = a)
+    ) OR EXISTS(
+        SELECT 1 FROM users
+        WHERE id IN (a, b) AND deleted_at IS NOT NULL
+    );
+$$;
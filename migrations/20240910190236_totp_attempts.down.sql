@@ -0,0 +1,4 @@
+-- Add down migration script here
+ALTER TABLE totp_secrets
+DROP COLUMN locked_until,
+DROP COLUMN failed_attempts;
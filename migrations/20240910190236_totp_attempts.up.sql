@@ -0,0 +1,4 @@
+-- Add up migration script here
+ALTER TABLE totp_secrets
+ADD COLUMN failed_attempts integer DEFAULT 0 NOT NULL,
+ADD COLUMN locked_until timestamp;
@@ -0,0 +1,9 @@
+-- Add up migration script here
+-- When a user last fetched another user's prekey bundle, so nobody can
+-- drain someone else's one-time prekeys.
+CREATE TABLE prekey_claims (
+    claimer_id uuid REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+    user_id uuid REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+    claimed_at timestamp DEFAULT now() NOT NULL,
+    PRIMARY KEY (claimer_id, user_id)
+);
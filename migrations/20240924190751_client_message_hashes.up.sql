@@ -0,0 +1,6 @@
+-- Add up migration script here
+-- Tells a retried send apart from a different message reusing its client id.
+ALTER TABLE messages ADD COLUMN content_hash bytea;
+
+UPDATE messages SET content_hash = sha256(content)
+WHERE client_id IS NOT NULL AND deleted_at IS NULL;
@@ -0,0 +1,148 @@
+use crate::config::Config;
+use rocket::{
+    fairing::AdHoc,
+    http::{ContentType, Status},
+    request::{self, FromRequest},
+    response::{self, Responder},
+    serde::{uuid::Uuid, Serialize},
+    tokio::io::AsyncRead,
+    Request, Response,
+};
+use std::sync::Arc;
+use storage::{LocalStorage, Storage};
+
+pub mod handlers;
+mod repo;
+pub mod storage;
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct Attachment {
+    id: Uuid,
+    chat_id: i32,
+    uploader_id: Uuid,
+    size: i64,
+    created_at: sqlx::types::chrono::NaiveDateTime,
+}
+
+/// Manages the storage attachment blobs are kept in.
+pub fn fairing() -> AdHoc {
+    AdHoc::on_ignite("Attachment Storage", |rocket| async {
+        let dir = rocket
+            .state::<Config>()
+            .expect("config is attached")
+            .attachments_dir
+            .clone();
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(dir));
+
+        rocket.manage(storage)
+    })
+}
+
+/// The raw `Range` header of a request, if any.
+pub struct RangeHeader<'r>(Option<&'r str>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RangeHeader<'r> {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request::Outcome::Success(RangeHeader(req.headers().get_one("Range")))
+    }
+}
+
+impl RangeHeader<'_> {
+    /// Resolves the header against a blob of `size` bytes into an inclusive
+    /// span. Only a single range is honoured; anything malformed or with
+    /// several ranges falls back to the whole blob, as the RFC allows.
+    fn resolve(&self, size: u64) -> Result<Option<(u64, u64)>, UnsatisfiableRange> {
+        let Some((start, end)) = self
+            .0
+            .and_then(|h| h.strip_prefix("bytes="))
+            .filter(|r| !r.contains(','))
+            .and_then(|r| r.trim().split_once('-'))
+        else {
+            return Ok(None);
+        };
+
+        let span = match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+            (Ok(start), Err(_)) if end.is_empty() => (start, size.saturating_sub(1)),
+            (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => {
+                (size.saturating_sub(suffix), size.saturating_sub(1))
+            }
+            _ => return Ok(None),
+        };
+
+        match span.0 < size {
+            true => Ok(Some(span)),
+            false => Err(UnsatisfiableRange { size }),
+        }
+    }
+}
+
+/// A range starting past the end of a blob of `size` bytes.
+pub struct UnsatisfiableRange {
+    size: u64,
+}
+
+impl<'r> Responder<'r, 'static> for UnsatisfiableRange {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        Response::build()
+            .status(Status::RangeNotSatisfiable)
+            .raw_header("Content-Range", format!("bytes */{}", self.size))
+            .ok()
+    }
+}
+
+/// Why a download was turned down.
+#[derive(Responder)]
+pub enum DownloadError {
+    RangeNotSatisfiable(UnsatisfiableRange),
+    Status(Status),
+}
+
+impl From<Status> for DownloadError {
+    fn from(status: Status) -> Self {
+        DownloadError::Status(status)
+    }
+}
+
+impl From<UnsatisfiableRange> for DownloadError {
+    fn from(range: UnsatisfiableRange) -> Self {
+        DownloadError::RangeNotSatisfiable(range)
+    }
+}
+
+/// A blob being streamed back, whole or as the requested span.
+pub struct Download {
+    reader: Box<dyn AsyncRead + Send + Unpin>,
+    size: u64,
+    span: Option<(u64, u64)>,
+}
+
+impl<'r> Responder<'r, 'static> for Download {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response
+            .header(ContentType::Binary)
+            .raw_header("Accept-Ranges", "bytes");
+
+        let length = match self.span {
+            Some((start, end)) => {
+                response.status(Status::PartialContent).raw_header(
+                    "Content-Range",
+                    format!("bytes {}-{}/{}", start, end, self.size),
+                );
+                end - start + 1
+            }
+            None => self.size,
+        };
+
+        response
+            .raw_header("Content-Length", length.to_string())
+            .streamed_body(self.reader)
+            .ok()
+    }
+}
@@ -0,0 +1,92 @@
+use super::{
+    repo,
+    storage::{self, Storage},
+    Attachment, Download, DownloadError, RangeHeader,
+};
+use crate::{
+    auth::AuthenticatedUser,
+    chat::{self, ChatRef},
+    config::Config,
+    db::Db,
+};
+use rocket::{
+    data::ToByteUnit,
+    http::Status,
+    serde::{
+        json::Json,
+        uuid::{Builder, Uuid},
+    },
+    Data, State,
+};
+use rocket_db_pools::Connection;
+use std::sync::Arc;
+
+/// Streams an encrypted blob to storage. The body is taken as is, so
+/// clients can send it chunked.
+#[rocket::post("/?<friend_id>", data = "<data>")]
+pub async fn upload(
+    mut db: Connection<Db>,
+    data: Data<'_>,
+    friend_id: Uuid,
+    user: AuthenticatedUser,
+    storage: &State<Arc<dyn Storage>>,
+    config: &State<Config>,
+) -> Result<Json<Attachment>, Status> {
+    let membership = chat::repo::get_membership(&mut db, user.id, ChatRef::Friend(friend_id))
+        .await
+        .or(Err(Status::InternalServerError))?
+        .ok_or(Status::Forbidden)?;
+
+    let id = Builder::from_random_bytes(rand::random()).into_uuid();
+    let limit = config.attachment_size_limit;
+
+    // Reading one byte past the limit tells an oversized body apart from
+    // one that fits exactly.
+    let size = storage
+        .write(id, &mut data.open((limit + 1).bytes()))
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    if size > limit {
+        storage::delete_all(storage, &[id]).await;
+        return Err(Status::PayloadTooLarge);
+    }
+
+    match repo::insert_attachment(&mut db, id, membership.chat_id, user.id, size as i64).await {
+        Ok(attachment) => Ok(Json(attachment)),
+        Err(_) => {
+            storage::delete_all(storage, &[id]).await;
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[rocket::get("/<id>")]
+pub async fn download(
+    mut db: Connection<Db>,
+    id: Uuid,
+    range: RangeHeader<'_>,
+    user: AuthenticatedUser,
+    storage: &State<Arc<dyn Storage>>,
+) -> Result<Download, DownloadError> {
+    chat::repo::get_membership(&mut db, user.id, ChatRef::Attachment(id))
+        .await
+        .or(Err(Status::InternalServerError))?
+        .ok_or(Status::NotFound)?;
+
+    let attachment = repo::get_attachment(&mut db, id)
+        .await
+        .or(Err(Status::InternalServerError))?
+        .ok_or(Status::NotFound)?;
+
+    let size = attachment.size as u64;
+    let span = range.resolve(size)?;
+    let (offset, len) = span.map_or((0, size), |(start, end)| (start, end - start + 1));
+
+    let reader = storage
+        .read(id, offset, len)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    Ok(Download { reader, size, span })
+}
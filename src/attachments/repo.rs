@@ -0,0 +1,34 @@
+use super::Attachment;
+use sqlx::{types::Uuid, PgConnection};
+
+pub async fn insert_attachment(
+    db: &mut PgConnection,
+    id: Uuid,
+    chat_id: i32,
+    uploader_id: Uuid,
+    size: i64,
+) -> Result<Attachment, sqlx::Error> {
+    sqlx::query_as!(
+        Attachment,
+        r#"
+        INSERT INTO attachments (id, chat_id, uploader_id, size)
+        VALUES ($1, $2, $3, $4)
+        RETURNING *;
+        "#,
+        id,
+        chat_id,
+        uploader_id,
+        size
+    )
+    .fetch_one(&mut *db)
+    .await
+}
+
+pub async fn get_attachment(
+    db: &mut PgConnection,
+    id: Uuid,
+) -> Result<Option<Attachment>, sqlx::Error> {
+    sqlx::query_as!(Attachment, r"SELECT * FROM attachments WHERE id = $1;", id)
+        .fetch_optional(&mut *db)
+        .await
+}
@@ -0,0 +1,99 @@
+use rocket::{
+    serde::uuid::Uuid,
+    tokio::{
+        fs::{self, File},
+        io::{self, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    },
+};
+use std::{io::SeekFrom, path::PathBuf, sync::Arc};
+
+/// Where attachment blobs live. Blobs are already encrypted by clients, so
+/// implementations only move bytes around.
+#[rocket::async_trait]
+pub trait Storage: Send + Sync {
+    /// Stores everything `reader` yields under `id` and returns its size.
+    async fn write(&self, id: Uuid, reader: &mut (dyn AsyncRead + Send + Unpin))
+        -> io::Result<u64>;
+
+    /// Streams `len` bytes of the blob starting at `offset`.
+    async fn read(
+        &self,
+        id: Uuid,
+        offset: u64,
+        len: u64,
+    ) -> io::Result<Box<dyn AsyncRead + Send + Unpin>>;
+
+    async fn delete(&self, id: Uuid) -> io::Result<()>;
+}
+
+/// Deletes the blobs of attachments without a row, whether it is already
+/// gone or was never stored. A blob left behind only costs space, so
+/// failures are logged and skipped.
+pub async fn delete_all(storage: &Arc<dyn Storage>, ids: &[Uuid]) {
+    for id in ids {
+        if let Err(e) = storage.delete(*id).await {
+            rocket::warn!("failed to delete attachment {}: {}", id, e);
+        }
+    }
+}
+
+/// Keeps each blob as a file named after its id under `root`.
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        LocalStorage { root: root.into() }
+    }
+
+    fn path(&self, id: Uuid) -> PathBuf {
+        self.root.join(id.to_string())
+    }
+}
+
+#[rocket::async_trait]
+impl Storage for LocalStorage {
+    async fn write(
+        &self,
+        id: Uuid,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> io::Result<u64> {
+        fs::create_dir_all(&self.root).await?;
+
+        // Written aside first so a failed upload never shows up under `id`.
+        let partial = self.root.join(format!("{}.part", id));
+        let mut file = File::create(&partial).await?;
+
+        let size = match io::copy(reader, &mut file).await {
+            Ok(size) => size,
+            Err(e) => {
+                drop(file);
+                fs::remove_file(&partial).await?;
+                return Err(e);
+            }
+        };
+
+        file.flush().await?;
+        file.sync_all().await?;
+        fs::rename(&partial, self.path(id)).await?;
+
+        Ok(size)
+    }
+
+    async fn read(
+        &self,
+        id: Uuid,
+        offset: u64,
+        len: u64,
+    ) -> io::Result<Box<dyn AsyncRead + Send + Unpin>> {
+        let mut file = File::open(self.path(id)).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+
+        Ok(Box::new(file.take(len)))
+    }
+
+    async fn delete(&self, id: Uuid) -> io::Result<()> {
+        fs::remove_file(self.path(id)).await
+    }
+}
@@ -1,17 +1,52 @@
 use crate::{config::Config, Validate};
-use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::{EncodingKey, Header};
 use rocket::{
+    fairing::AdHoc,
     http::Status,
     request::{FromRequest, Outcome},
-    serde::{uuid::Uuid, Deserialize, Serialize},
-    Request,
+    serde::{json::Json, uuid::Uuid, Deserialize, Serialize},
+    Request, Responder,
 };
 use rocket_db_pools::sqlx;
 
 pub mod handlers;
+pub mod password;
+mod recovery;
 mod repo;
+mod signing;
+mod totp;
 mod validators;
 
+pub use signing::TokenKeys;
+
+/// How long a signin waits for its second factor.
+const CHALLENGE_TTL_SEC: i32 = 300;
+
+/// Wrong codes a signin challenge takes before it stops working.
+const MAX_CHALLENGE_ATTEMPTS: i32 = 5;
+
+/// Wrong second factor codes in a row before a user's codes stop being
+/// checked for `TOTP_LOCKOUT_SEC`.
+const MAX_TOTP_ATTEMPTS: i32 = 5;
+
+const TOTP_LOCKOUT_SEC: i32 = 300;
+
+/// Loads the keys access tokens are signed with, refusing to launch with
+/// an invalid set.
+pub fn fairing() -> AdHoc {
+    AdHoc::try_on_ignite("Access Token Keys", |rocket| async {
+        let config = rocket.state::<Config>().expect("config is attached");
+
+        match TokenKeys::from_config(config) {
+            Ok(keys) => Ok(rocket.manage(keys)),
+            Err(e) => {
+                rocket::error!("{}", e);
+                Err(rocket)
+            }
+        }
+    })
+}
+
 #[derive(Deserialize)]
 #[serde(crate = "rocket::serde")]
 #[serde(rename_all = "camelCase")]
@@ -31,15 +66,25 @@ impl Validate for SignUp {
 
 #[derive(Deserialize)]
 #[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
 pub struct SignIn {
     username: String,
     password: String,
+
+    /// Device registered on a previous signin. A new one is registered
+    /// when absent.
+    device_id: Option<Uuid>,
+    device_name: Option<String>,
 }
 
 impl Validate for SignIn {
     fn validate(&self) -> bool {
         validators::is_valid_username(&self.username)
             && validators::is_valid_password(&self.password)
+            && self
+                .device_name
+                .as_deref()
+                .is_none_or(validators::is_valid_device_name)
     }
 }
 
@@ -62,6 +107,16 @@ pub struct AuthenticatedUser {
     pub created_at: sqlx::types::chrono::NaiveDateTime,
 }
 
+/// A new account, with the recovery codes that can reset its password.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct SignedUp {
+    #[serde(flatten)]
+    user: AuthenticatedUser,
+    recovery_codes: Vec<String>,
+}
+
 impl AuthenticatedUser {
     fn from_user(user: &User) -> Self {
         AuthenticatedUser {
@@ -78,8 +133,39 @@ impl<'r> FromRequest<'r> for AuthenticatedUser {
     type Error = ();
 
     async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Claims::from_authorization(req).map(|c| c.user)
+    }
+}
+
+/// The user together with the device its access token was issued to.
+pub struct AuthenticatedDevice {
+    pub id: Uuid,
+    pub user: AuthenticatedUser,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthenticatedDevice {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Claims::from_authorization(req).map(|c| AuthenticatedDevice {
+            id: c.device_id,
+            user: c.user,
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Claims {
+    user: AuthenticatedUser,
+    device_id: Uuid,
+    exp: usize,
+}
+
+impl Claims {
+    fn from_authorization(req: &Request<'_>) -> Outcome<Self, ()> {
         let auth_header = req.headers().get_one("Authorization");
-        let config = req.rocket().state::<Config>().unwrap();
 
         match auth_header {
             None => Outcome::Forward(Status::Unauthorized),
@@ -91,36 +177,180 @@ impl<'r> FromRequest<'r> for AuthenticatedUser {
                 }
 
                 let token = parts[1];
-                let decode_result = jsonwebtoken::decode::<Claims>(
-                    token,
-                    &DecodingKey::from_secret(config.access_token_secret.as_bytes()),
-                    &Validation::new(jsonwebtoken::Algorithm::HS256),
-                );
-
-                match decode_result {
-                    Ok(payload) => Outcome::Success(payload.claims.user),
+                let keys = req.rocket().state::<TokenKeys>().unwrap();
+
+                match keys.decode(token) {
+                    Ok(claims) => Outcome::Success(claims),
                     Err(_) => Outcome::Forward(Status::Unauthorized),
                 }
             }
         }
     }
+
+    /// Signs with a shared secret, as for refresh tokens. Access tokens go
+    /// through `TokenKeys`.
+    pub fn encode(&self, secret: &[u8]) -> Result<String, jsonwebtoken::errors::Error> {
+        jsonwebtoken::encode(&Header::default(), self, &EncodingKey::from_secret(secret))
+    }
 }
 
-#[derive(Deserialize, Serialize)]
+/// A signed in device, as listed to its owner.
+#[derive(Serialize)]
 #[serde(crate = "rocket::serde")]
-pub struct Claims {
-    user: AuthenticatedUser,
-    exp: usize,
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+    id: i32,
+    device_id: Uuid,
+    device_name: String,
+    created_at: sqlx::types::chrono::NaiveDateTime,
+    refreshed_at: sqlx::types::chrono::NaiveDateTime,
+    user_agent: Option<String>,
+    ip: Option<String>,
+
+    /// Whether this is the session of the device asking.
+    current: bool,
 }
 
-impl Claims {
-    pub fn encode(&self, secret: &[u8]) -> Result<String, jsonwebtoken::errors::Error> {
-        jsonwebtoken::encode(&Header::default(), self, &EncodingKey::from_secret(secret))
+/// Where a signin or refresh came from, recorded on the session.
+pub struct ClientOrigin {
+    user_agent: Option<String>,
+    ip: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientOrigin {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(ClientOrigin {
+            user_agent: req
+                .headers()
+                .get_one("User-Agent")
+                .map(|ua| ua.chars().take(256).collect()),
+            ip: req.client_ip().map(|ip| ip.to_string()),
+        })
     }
 }
 
 #[derive(Deserialize, Serialize)]
 #[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
 pub struct AccessToken {
     pub token: String,
+    pub device_id: Uuid,
+}
+
+/// Returned by signin instead of tokens when the user has 2FA enabled.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct SecondFactorChallenge {
+    challenge: String,
+    expires_at: sqlx::types::chrono::NaiveDateTime,
+}
+
+#[derive(Responder)]
+pub enum SignInResponse {
+    Token(Json<AccessToken>),
+
+    #[response(status = 202)]
+    SecondFactorRequired(Json<SecondFactorChallenge>),
+}
+
+/// Completes a signin that was answered with a challenge.
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct SecondFactor {
+    challenge: String,
+
+    /// A current TOTP code or an unused recovery code.
+    code: String,
+}
+
+/// A signin still owing its second factor.
+struct Challenge {
+    user_id: Uuid,
+    device_id: Option<Uuid>,
+    device_name: Option<String>,
+}
+
+struct Totp {
+    secret: Vec<u8>,
+    confirmed_at: Option<sqlx::types::chrono::NaiveDateTime>,
+    locked: bool,
+}
+
+/// A freshly generated TOTP secret, both raw and as a provisioning URI.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct TotpEnrollment {
+    secret: String,
+    uri: String,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct TotpCode {
+    code: String,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct PasswordChange {
+    password: String,
+    new_password: String,
+    new_password_check: String,
+}
+
+impl Validate for PasswordChange {
+    fn validate(&self) -> bool {
+        validators::is_valid_password(&self.new_password)
+            && self.new_password == self.new_password_check
+    }
+}
+
+/// Sets a new password for a user who lost theirs, spending one of their
+/// recovery codes.
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct AccountRecovery {
+    username: String,
+    recovery_code: String,
+
+    /// A current TOTP code, needed on top of the recovery code while 2FA is
+    /// enabled.
+    code: Option<String>,
+
+    new_password: String,
+    new_password_check: String,
+}
+
+impl Validate for AccountRecovery {
+    fn validate(&self) -> bool {
+        validators::is_valid_username(&self.username)
+            && validators::is_valid_password(&self.new_password)
+            && self.new_password == self.new_password_check
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct PasswordCheck {
+    password: String,
+
+    /// A current TOTP code, needed on top of the password while 2FA is
+    /// enabled.
+    code: Option<String>,
+}
+
+/// Shown once; only their hashes are kept.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct RecoveryCodes {
+    recovery_codes: Vec<String>,
 }
@@ -1,62 +1,44 @@
-use super::{repo, AccessToken, AuthenticatedUser, Claims, Validate};
+use super::{
+    password, recovery, repo, totp, AccessToken, AccountRecovery, AuthenticatedDevice,
+    AuthenticatedUser, Claims, ClientOrigin, PasswordChange, PasswordCheck, RecoveryCodes,
+    SecondFactor, Session, SignInResponse, SignedUp, TokenKeys, TotpCode, TotpEnrollment, User,
+    Validate,
+};
 use crate::{
     auth::{SignIn, SignUp},
     config::Config,
     db::Db,
     utils,
 };
-use argon2::{
-    password_hash::SaltString, Algorithm, Argon2, Params, PasswordHash, PasswordHasher,
-    PasswordVerifier, Version,
-};
-use rand::rngs::OsRng;
+use jsonwebtoken::jwk::JwkSet;
 use rocket::{
     http::{Cookie, CookieJar, Status},
     serde::json::Json,
     State,
 };
 use rocket_db_pools::Connection;
-use std::sync::Arc;
+use sqlx::{types::Uuid, PgConnection};
 
 #[rocket::post("/signup", data = "<body>")]
 pub async fn signup(
     mut db: Connection<Db>,
     body: Json<SignUp>,
     config: &State<Config>,
-) -> Result<Json<AuthenticatedUser>, Status> {
+) -> Result<Json<SignedUp>, Status> {
     if !body.validate() {
         return Err(Status::UnprocessableEntity);
     }
 
-    let body = Arc::new(body);
-    let body_clone = body.clone();
-    let argon_secret_clone = config.argon_secret.clone();
-
-    let password_hash = rocket::tokio::task::spawn_blocking(move || {
-        let argon = Argon2::new_with_secret(
-            argon_secret_clone.as_bytes(),
-            Algorithm::Argon2id,
-            Version::V0x13,
-            Params::default(),
-        )
-        .or(Err(Status::InternalServerError))?;
-
-        let salt = SaltString::generate(&mut OsRng);
-
-        match argon.hash_password(body_clone.password.as_bytes(), &salt) {
-            Err(_) => Err(Status::InternalServerError),
-            Ok(h) => Ok(h.to_string()),
-        }
-    })
-    .await
-    .or(Err(Status::InternalServerError))??;
-
+    let password_hash = password::hash(config, &body.password).await?;
     let pbkdf2_salt = utils::compute_random_32_bytes_key();
+    let (recovery_codes, hashes) = recovery::generate_codes();
+
     let user = repo::insert_user(
         &mut db,
-        &body.clone().username,
+        &body.username,
         &password_hash,
         &pbkdf2_salt,
+        &hashes,
     )
     .await
     .map_err(|e| match e.as_database_error() {
@@ -64,7 +46,10 @@ pub async fn signup(
         _ => Status::InternalServerError,
     })?;
 
-    Ok(Json(user))
+    Ok(Json(SignedUp {
+        user,
+        recovery_codes,
+    }))
 }
 
 #[rocket::post("/signin", data = "<body>")]
@@ -72,38 +57,22 @@ pub async fn signin(
     mut db: Connection<Db>,
     cookies: &CookieJar<'_>,
     body: Json<SignIn>,
+    origin: ClientOrigin,
     config: &State<Config>,
-) -> Result<Json<AccessToken>, Status> {
+    keys: &State<TokenKeys>,
+) -> Result<SignInResponse, Status> {
     if !body.validate() {
         return Err(Status::UnprocessableEntity);
     }
 
-    let body = Arc::new(body);
-    let body_clone = body.clone();
-    let argon_secret_clone = config.argon_secret.clone();
+    let user = repo::get_user_by_username(&mut db, &body.username)
+        .await
+        .or(Err(Status::InternalServerError))?
+        .ok_or(Status::Unauthorized)?;
 
-    let user = Arc::new(
-        repo::get_user_by_username(&mut db, &body_clone.username)
-            .await
-            .or(Err(Status::InternalServerError))?
-            .ok_or(Status::Unauthorized)?,
-    );
-
-    let user_clone = user.clone();
-
-    rocket::tokio::task::spawn_blocking(move || {
-        let argon = Argon2::new_with_secret(
-            argon_secret_clone.as_bytes(),
-            Algorithm::Argon2id,
-            Version::V0x13,
-            Params::default(),
-        )?;
-        let password_hash = PasswordHash::new(&user_clone.password)?;
-        argon.verify_password(body_clone.password.as_bytes(), &password_hash)
-    })
-    .await
-    .or(Err(Status::InternalServerError))?
-    .or(Err(Status::Unauthorized))?;
+    if !password::verify(config, &user.password, &body.password).await? {
+        return Err(Status::Unauthorized);
+    }
 
     if let Some(c) = cookies.get_private("session") {
         repo::delete_all_user_sessions_on_reuse(&mut db, user.id, c.value())
@@ -111,15 +80,128 @@ pub async fn signin(
             .or(Err(Status::InternalServerError))?;
     }
 
+    let totp = repo::get_totp(&mut db, user.id)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    if totp.is_some_and(|t| t.confirmed_at.is_some()) {
+        let challenge = repo::create_challenge(
+            &mut db,
+            &utils::compute_random_32_bytes_key(),
+            user.id,
+            body.device_id,
+            body.device_name.as_deref(),
+        )
+        .await
+        .map_err(|e| match e.as_database_error() {
+            Some(e) if e.is_foreign_key_violation() => Status::NotFound,
+            _ => Status::InternalServerError,
+        })?;
+
+        return Ok(SignInResponse::SecondFactorRequired(Json(challenge)));
+    }
+
+    let device_id = pick_device(
+        &mut db,
+        user.id,
+        body.device_id,
+        body.device_name.as_deref(),
+    )
+    .await?;
+    let access_token =
+        start_session(&mut db, cookies, config, keys, &origin, &user, device_id).await?;
+
+    Ok(SignInResponse::Token(Json(access_token)))
+}
+
+/// Answers the challenge a signin got back, finishing it.
+#[rocket::post("/signin/second-factor", data = "<body>")]
+pub async fn signin_second_factor(
+    mut db: Connection<Db>,
+    cookies: &CookieJar<'_>,
+    body: Json<SecondFactor>,
+    origin: ClientOrigin,
+    config: &State<Config>,
+    keys: &State<TokenKeys>,
+) -> Result<Json<AccessToken>, Status> {
+    let challenge = repo::attempt_challenge(&mut db, &body.challenge)
+        .await
+        .or(Err(Status::InternalServerError))?
+        .ok_or(Status::Unauthorized)?;
+
+    if !check_second_factor(&mut db, challenge.user_id, &body.code, true).await? {
+        return Err(Status::Unauthorized);
+    }
+
+    repo::delete_challenge(&mut db, &body.challenge)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    let user = repo::get_user_by_id(&mut db, challenge.user_id)
+        .await
+        .or(Err(Status::InternalServerError))?
+        .ok_or(Status::Unauthorized)?;
+
+    let device_id = pick_device(
+        &mut db,
+        user.id,
+        challenge.device_id,
+        challenge.device_name.as_deref(),
+    )
+    .await?;
+    let access_token =
+        start_session(&mut db, cookies, config, keys, &origin, &user, device_id).await?;
+
+    Ok(Json(access_token))
+}
+
+/// The device to sign in on, registering a new one when none is given.
+async fn pick_device(
+    db: &mut PgConnection,
+    user_id: Uuid,
+    device_id: Option<Uuid>,
+    device_name: Option<&str>,
+) -> Result<Uuid, Status> {
+    match device_id {
+        Some(id) => {
+            let result = repo::touch_device(db, user_id, id)
+                .await
+                .or(Err(Status::InternalServerError))?;
+
+            if result.rows_affected() == 0 {
+                return Err(Status::NotFound);
+            }
+
+            Ok(id)
+        }
+        None => {
+            let name = device_name.unwrap_or("Unnamed device");
+
+            repo::create_device(db, user_id, name)
+                .await
+                .or(Err(Status::InternalServerError))
+        }
+    }
+}
+
+/// Issues the token pair for `device_id`.
+async fn start_session(
+    db: &mut PgConnection,
+    cookies: &CookieJar<'_>,
+    config: &Config,
+    keys: &TokenKeys,
+    origin: &ClientOrigin,
+    user: &User,
+    device_id: Uuid,
+) -> Result<AccessToken, Status> {
     let now = chrono::Utc::now().timestamp() as usize;
     let mut claims = Claims {
-        user: AuthenticatedUser::from_user(&user),
+        user: AuthenticatedUser::from_user(user),
+        device_id,
         exp: now + config.access_token_ttl_sec as usize,
     };
 
-    let access_token = claims
-        .encode(config.access_token_secret.as_bytes())
-        .or(Err(Status::InternalServerError))?;
+    let access_token = keys.encode(&claims).or(Err(Status::InternalServerError))?;
 
     claims.exp = now + config.refresh_token_ttl_sec as usize;
 
@@ -127,7 +209,7 @@ pub async fn signin(
         .encode(config.refresh_token_secret.as_bytes())
         .or(Err(Status::InternalServerError))?;
 
-    repo::create_session(&mut db, user.id, &refresh_token)
+    repo::create_session(db, user.id, device_id, &refresh_token, origin)
         .await
         .or(Err(Status::InternalServerError))?;
 
@@ -135,9 +217,48 @@ pub async fn signin(
         rocket::time::Duration::seconds(config.refresh_token_ttl_sec as i64),
     ));
 
-    Ok(Json(AccessToken {
+    Ok(AccessToken {
         token: access_token,
-    }))
+        device_id,
+    })
+}
+
+/// Accepts a TOTP code for a step not used before or, if allowed, an unused
+/// recovery code. Either is spent on success. Too many failures in a row
+/// lock the user's codes out for a while.
+async fn check_second_factor(
+    db: &mut PgConnection,
+    user_id: Uuid,
+    code: &str,
+    allow_recovery_code: bool,
+) -> Result<bool, Status> {
+    let totp = repo::get_totp(db, user_id)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    let Some(totp) = totp.filter(|t| t.confirmed_at.is_some()) else {
+        return Ok(false);
+    };
+
+    if totp.locked {
+        return Err(Status::TooManyRequests);
+    }
+
+    let now = chrono::Utc::now().timestamp() as u64;
+    let accepted = match totp::verify(&totp.secret, code, now) {
+        Some(step) => repo::use_totp_step(db, user_id, step).await,
+        None if allow_recovery_code => {
+            repo::use_recovery_code(db, user_id, &recovery::hash_code(code)).await
+        }
+        None => Ok(false),
+    }
+    .or(Err(Status::InternalServerError))?;
+
+    repo::record_totp_attempt(db, user_id, accepted)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    Ok(accepted)
 }
 
 #[rocket::post("/refresh")]
@@ -145,7 +266,9 @@ pub async fn refresh(
     mut db: Connection<Db>,
     user: AuthenticatedUser,
     cookies: &CookieJar<'_>,
+    origin: ClientOrigin,
     config: &State<Config>,
+    keys: &State<TokenKeys>,
 ) -> Result<Json<AccessToken>, Status> {
     let session = cookies.get_private("session");
     let user_id = user.id;
@@ -165,15 +288,23 @@ pub async fn refresh(
         return Err(Status::Unauthorized);
     }
 
+    let device_id = repo::get_session_device(&mut db, user_id, session.as_ref().unwrap().value())
+        .await
+        .or(Err(Status::InternalServerError))?
+        .ok_or(Status::Unauthorized)?;
+
+    repo::touch_device(&mut db, user_id, device_id)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
     let now = chrono::Utc::now().timestamp() as usize;
     let mut claims = Claims {
         user,
+        device_id,
         exp: now + config.access_token_ttl_sec as usize,
     };
 
-    let access_token = claims
-        .encode(config.access_token_secret.as_bytes())
-        .or(Err(Status::InternalServerError))?;
+    let access_token = keys.encode(&claims).or(Err(Status::InternalServerError))?;
 
     claims.exp = now + config.refresh_token_ttl_sec as usize;
 
@@ -181,9 +312,15 @@ pub async fn refresh(
         .encode(config.refresh_token_secret.as_bytes())
         .or(Err(Status::InternalServerError))?;
 
-    repo::update_session(&mut db, user_id, &session.unwrap().value(), &refresh_token)
-        .await
-        .or(Err(Status::InternalServerError))?;
+    repo::update_session(
+        &mut db,
+        user_id,
+        session.unwrap().value(),
+        &refresh_token,
+        &origin,
+    )
+    .await
+    .or(Err(Status::InternalServerError))?;
 
     cookies.add_private(Cookie::build(("session", refresh_token)).max_age(
         rocket::time::Duration::seconds(config.refresh_token_ttl_sec as i64),
@@ -191,6 +328,7 @@ pub async fn refresh(
 
     Ok(Json(AccessToken {
         token: access_token,
+        device_id,
     }))
 }
 
@@ -207,7 +345,7 @@ pub async fn logout(
         cookies.remove_private("session");
 
         match result {
-            Err(_) => return Err(Status::InternalServerError),
+            Err(_) => Err(Status::InternalServerError),
             Ok(r) if r.rows_affected() == 0 => {
                 repo::delete_all_user_sessions_on_reuse(&mut db, user_id, c.value())
                     .await
@@ -221,3 +359,241 @@ pub async fn logout(
         Err(Status::Unauthorized)
     }
 }
+
+#[rocket::get("/sessions")]
+pub async fn get_sessions(
+    mut db: Connection<Db>,
+    device: AuthenticatedDevice,
+) -> Result<Json<Vec<Session>>, Status> {
+    let sessions = repo::get_sessions(&mut db, device.user.id, device.id)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    Ok(Json(sessions))
+}
+
+#[rocket::delete("/sessions/<id>")]
+pub async fn revoke_session(
+    mut db: Connection<Db>,
+    id: i32,
+    device: AuthenticatedDevice,
+) -> Result<Json<Session>, Status> {
+    let session = repo::revoke_session(&mut db, device.user.id, device.id, id)
+        .await
+        .or(Err(Status::InternalServerError))?
+        .ok_or(Status::NotFound)?;
+
+    Ok(Json(session))
+}
+
+/// Logs out everywhere else, returning the sessions that were revoked.
+#[rocket::delete("/sessions")]
+pub async fn revoke_other_sessions(
+    mut db: Connection<Db>,
+    device: AuthenticatedDevice,
+) -> Result<Json<Vec<Session>>, Status> {
+    let sessions = repo::revoke_other_sessions(&mut db, device.user.id, device.id)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    Ok(Json(sessions))
+}
+
+/// Starts enrolling in 2FA. Nothing changes for signin until the secret is
+/// confirmed with a code.
+#[rocket::post("/totp")]
+pub async fn enroll_totp(
+    mut db: Connection<Db>,
+    user: AuthenticatedUser,
+) -> Result<Json<TotpEnrollment>, Status> {
+    let secret = totp::generate_secret();
+
+    let stored = repo::upsert_pending_totp(&mut db, user.id, &secret)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    if !stored {
+        return Err(Status::Conflict);
+    }
+
+    Ok(Json(TotpEnrollment {
+        secret: totp::encode_secret(&secret),
+        uri: totp::provisioning_uri(&secret, &user.username),
+    }))
+}
+
+/// Enables 2FA once the authenticator proves it holds the secret, handing
+/// out a new set of recovery codes in place of the old one.
+#[rocket::post("/totp/confirm", data = "<body>")]
+pub async fn confirm_totp(
+    mut db: Connection<Db>,
+    body: Json<TotpCode>,
+    user: AuthenticatedUser,
+) -> Result<Json<RecoveryCodes>, Status> {
+    let totp = repo::get_totp(&mut db, user.id)
+        .await
+        .or(Err(Status::InternalServerError))?
+        .filter(|t| t.confirmed_at.is_none())
+        .ok_or(Status::NotFound)?;
+
+    let now = chrono::Utc::now().timestamp() as u64;
+    let step = totp::verify(&totp.secret, &body.code, now).ok_or(Status::Forbidden)?;
+
+    let (recovery_codes, hashes) = recovery::generate_codes();
+
+    let confirmed = repo::confirm_totp(&mut db, user.id, step, &hashes)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    if !confirmed {
+        return Err(Status::NotFound);
+    }
+
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+/// Turns 2FA off, which takes a fresh code from the authenticator as well as
+/// a valid access token. Recovery codes are only good for signing in.
+#[rocket::delete("/totp", data = "<body>")]
+pub async fn disable_totp(
+    mut db: Connection<Db>,
+    body: Json<TotpCode>,
+    user: AuthenticatedUser,
+) -> Result<(), Status> {
+    if !check_second_factor(&mut db, user.id, &body.code, false).await? {
+        return Err(Status::Forbidden);
+    }
+
+    repo::delete_totp(&mut db, user.id)
+        .await
+        .or(Err(Status::InternalServerError))
+}
+
+/// Changes the password of a signed in user, signing out every other
+/// device.
+#[rocket::put("/password", data = "<body>")]
+pub async fn change_password(
+    mut db: Connection<Db>,
+    body: Json<PasswordChange>,
+    device: AuthenticatedDevice,
+    config: &State<Config>,
+) -> Result<(), Status> {
+    if !body.validate() {
+        return Err(Status::UnprocessableEntity);
+    }
+
+    if !password::check(&mut db, config, device.user.id, &body.password).await? {
+        return Err(Status::Forbidden);
+    }
+
+    let password_hash = password::hash(config, &body.new_password).await?;
+
+    repo::update_password(&mut db, device.user.id, &password_hash, Some(device.id))
+        .await
+        .or(Err(Status::InternalServerError))
+}
+
+/// Resets a forgotten password with a recovery code. While 2FA is enabled
+/// this takes a TOTP code too, and a wrong recovery code counts towards the
+/// same lockout. Every session is revoked.
+#[rocket::post("/recover", data = "<body>")]
+pub async fn recover_account(
+    mut db: Connection<Db>,
+    body: Json<AccountRecovery>,
+    config: &State<Config>,
+) -> Result<(), Status> {
+    if !body.validate() {
+        return Err(Status::UnprocessableEntity);
+    }
+
+    let user = repo::get_user_by_username(&mut db, &body.username)
+        .await
+        .or(Err(Status::InternalServerError))?
+        .ok_or(Status::Unauthorized)?;
+
+    let totp = repo::get_totp(&mut db, user.id)
+        .await
+        .or(Err(Status::InternalServerError))?
+        .filter(|t| t.confirmed_at.is_some());
+
+    if totp.as_ref().is_some_and(|t| t.locked) {
+        return Err(Status::TooManyRequests);
+    }
+
+    let code_hash = recovery::hash_code(&body.recovery_code);
+
+    let has_code = repo::has_recovery_code(&mut db, user.id, &code_hash)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    if !has_code {
+        if totp.is_some() {
+            repo::record_totp_attempt(&mut db, user.id, false)
+                .await
+                .or(Err(Status::InternalServerError))?;
+        }
+
+        return Err(Status::Unauthorized);
+    }
+
+    if totp.is_some() {
+        let code = body.code.as_deref().ok_or(Status::Unauthorized)?;
+
+        if !check_second_factor(&mut db, user.id, code, false).await? {
+            return Err(Status::Unauthorized);
+        }
+    }
+
+    let password_hash = password::hash(config, &body.new_password).await?;
+
+    let recovered = repo::recover_account(&mut db, user.id, &code_hash, &password_hash)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    if !recovered {
+        return Err(Status::Unauthorized);
+    }
+
+    Ok(())
+}
+
+/// Replaces the recovery codes, used or not, with a new set. While 2FA is
+/// enabled this takes a TOTP code too, as a recovery code would otherwise
+/// stand in for the authenticator.
+#[rocket::post("/recovery-codes", data = "<body>")]
+pub async fn regenerate_recovery_codes(
+    mut db: Connection<Db>,
+    body: Json<PasswordCheck>,
+    user: AuthenticatedUser,
+    config: &State<Config>,
+) -> Result<Json<RecoveryCodes>, Status> {
+    if !password::check(&mut db, config, user.id, &body.password).await? {
+        return Err(Status::Forbidden);
+    }
+
+    let totp = repo::get_totp(&mut db, user.id)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    if totp.is_some_and(|t| t.confirmed_at.is_some()) {
+        let code = body.code.as_deref().ok_or(Status::Forbidden)?;
+
+        if !check_second_factor(&mut db, user.id, code, false).await? {
+            return Err(Status::Forbidden);
+        }
+    }
+
+    let (recovery_codes, hashes) = recovery::generate_codes();
+
+    repo::replace_recovery_codes(&mut db, user.id, &hashes)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+/// Public keys access tokens can be verified with, for other services.
+#[rocket::get("/.well-known/jwks.json")]
+pub fn jwks(keys: &State<TokenKeys>) -> Json<JwkSet> {
+    Json(keys.jwks().clone())
+}
@@ -0,0 +1,73 @@
+use super::repo;
+use crate::config::Config;
+use argon2::{
+    password_hash::SaltString, Algorithm, Argon2, Params, PasswordHash, PasswordHasher,
+    PasswordVerifier, Version,
+};
+use rand::rngs::OsRng;
+use rocket::http::Status;
+use sqlx::{types::Uuid, PgConnection};
+
+/// Argon2id hash of `password`, keyed with the configured secret.
+pub async fn hash(config: &Config, password: &str) -> Result<String, Status> {
+    let argon_secret = config.argon_secret.clone();
+    let password = password.to_owned();
+
+    rocket::tokio::task::spawn_blocking(move || {
+        let argon = Argon2::new_with_secret(
+            argon_secret.as_bytes(),
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::default(),
+        )
+        .or(Err(Status::InternalServerError))?;
+
+        let salt = SaltString::generate(&mut OsRng);
+
+        match argon.hash_password(password.as_bytes(), &salt) {
+            Err(_) => Err(Status::InternalServerError),
+            Ok(h) => Ok(h.to_string()),
+        }
+    })
+    .await
+    .or(Err(Status::InternalServerError))?
+}
+
+pub async fn verify(config: &Config, hash: &str, password: &str) -> Result<bool, Status> {
+    let argon_secret = config.argon_secret.clone();
+    let hash = hash.to_owned();
+    let password = password.to_owned();
+
+    rocket::tokio::task::spawn_blocking(move || {
+        let argon = Argon2::new_with_secret(
+            argon_secret.as_bytes(),
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::default(),
+        )
+        .or(Err(Status::InternalServerError))?;
+        let password_hash = PasswordHash::new(&hash).or(Err(Status::InternalServerError))?;
+
+        Ok(argon
+            .verify_password(password.as_bytes(), &password_hash)
+            .is_ok())
+    })
+    .await
+    .or(Err(Status::InternalServerError))?
+}
+
+/// Whether `password` is the current one of `user_id`, for actions that ask
+/// for it again on top of a valid access token.
+pub async fn check(
+    db: &mut PgConnection,
+    config: &Config,
+    user_id: Uuid,
+    password: &str,
+) -> Result<bool, Status> {
+    let user = repo::get_user_by_id(db, user_id)
+        .await
+        .or(Err(Status::InternalServerError))?
+        .ok_or(Status::Unauthorized)?;
+
+    verify(config, &user.password, password).await
+}
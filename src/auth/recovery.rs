@@ -0,0 +1,37 @@
+use data_encoding::BASE32_NOPAD;
+use sha2::{Digest, Sha256};
+
+const CODE_COUNT: usize = 10;
+
+/// A fresh recovery code, in groups of four for readability.
+fn generate_code() -> String {
+    let code = BASE32_NOPAD
+        .encode(&rand::random::<[u8; 10]>())
+        .to_lowercase();
+
+    code.as_bytes()
+        .chunks(4)
+        .map(|c| std::str::from_utf8(c).unwrap())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// A full set of codes to show the user, along with the hashes to store.
+pub fn generate_codes() -> (Vec<String>, Vec<Vec<u8>>) {
+    let codes: Vec<String> = (0..CODE_COUNT).map(|_| generate_code()).collect();
+    let hashes = codes.iter().map(|c| hash_code(c)).collect();
+
+    (codes, hashes)
+}
+
+/// Recovery codes carry 80 random bits, so a plain hash is enough to keep
+/// them safe at rest. Dashes and case don't matter when typing one in.
+pub fn hash_code(code: &str) -> Vec<u8> {
+    let normalized: String = code
+        .chars()
+        .filter(|c| *c != '-')
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    Sha256::digest(normalized.as_bytes()).to_vec()
+}
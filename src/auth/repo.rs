@@ -1,13 +1,20 @@
-use super::{AuthenticatedUser, User};
-use sqlx::{postgres::PgQueryResult, types::Uuid, PgConnection};
+use super::{
+    AuthenticatedUser, Challenge, ClientOrigin, SecondFactorChallenge, Session, Totp, User,
+    CHALLENGE_TTL_SEC, MAX_CHALLENGE_ATTEMPTS, MAX_TOTP_ATTEMPTS, TOTP_LOCKOUT_SEC,
+};
+use sqlx::{postgres::PgQueryResult, types::Uuid, Connection, PgConnection};
 
+/// Creates the user along with their first set of recovery codes.
 pub async fn insert_user(
     db: &mut PgConnection,
     username: &str,
     password_hash: &str,
     pbkdf2_salt: &str,
+    recovery_code_hashes: &[Vec<u8>],
 ) -> Result<AuthenticatedUser, sqlx::Error> {
-    sqlx::query_as!(
+    let mut tx = db.begin().await?;
+
+    let user = sqlx::query_as!(
         AuthenticatedUser,
         r#"
         INSERT INTO users (username, password, pbkdf2_salt)
@@ -22,17 +29,45 @@ pub async fn insert_user(
         password_hash,
         pbkdf2_salt
     )
-    .fetch_one(&mut *db)
-    .await
+    .fetch_one(&mut *tx)
+    .await?;
+
+    replace_recovery_codes(&mut tx, user.id, recovery_code_hashes).await?;
+
+    tx.commit().await?;
+
+    Ok(user)
 }
 
 pub async fn get_user_by_username(
     db: &mut PgConnection,
     username: &str,
 ) -> Result<Option<User>, sqlx::Error> {
-    sqlx::query_as!(User, r"SELECT * FROM users WHERE username = $1;", username,)
-        .fetch_optional(&mut *db)
-        .await
+    sqlx::query_as!(
+        User,
+        r#"
+        SELECT id, username, password, pbkdf2_salt, created_at
+        FROM users
+        WHERE username = $1 AND deleted_at IS NULL;
+        "#,
+        username
+    )
+    .fetch_optional(&mut *db)
+    .await
+}
+
+pub async fn get_user_by_id(db: &mut PgConnection, id: Uuid) -> Result<Option<User>, sqlx::Error> {
+    sqlx::query_as!(
+        User,
+        r#"
+        SELECT id, username, password, pbkdf2_salt, created_at
+        FROM users
+        WHERE id = $1 AND deleted_at IS NULL;
+        "#,
+        id
+    )
+    .fetch_optional(&mut *db)
+    .await
 }
 
 pub async fn delete_all_user_sessions_on_reuse(
@@ -56,37 +91,102 @@ pub async fn delete_all_user_sessions_on_reuse(
     .await
 }
 
+pub async fn create_device(
+    db: &mut PgConnection,
+    user_id: Uuid,
+    name: &str,
+) -> Result<Uuid, sqlx::Error> {
+    sqlx::query_scalar!(
+        r"INSERT INTO devices (user_id, name) VALUES ($1, $2) RETURNING id;",
+        user_id,
+        name
+    )
+    .fetch_one(&mut *db)
+    .await
+}
+
+pub async fn touch_device(
+    db: &mut PgConnection,
+    user_id: Uuid,
+    device_id: Uuid,
+) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!(
+        r"UPDATE devices SET last_seen_at = NOW() WHERE id = $2 AND user_id = $1;",
+        user_id,
+        device_id
+    )
+    .execute(&mut *db)
+    .await
+}
+
+/// A device holds at most one session, so signing in again on it replaces
+/// the previous one.
 pub async fn create_session(
     db: &mut PgConnection,
     user_id: Uuid,
+    device_id: Uuid,
     token: &str,
+    origin: &ClientOrigin,
 ) -> Result<PgQueryResult, sqlx::Error> {
     sqlx::query!(
-        r"INSERT INTO sessions (user_id, token) VALUES ($1, $2);",
+        r#"
+        INSERT INTO sessions (user_id, device_id, token, user_agent, ip)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (device_id) DO UPDATE
+        SET
+            token = EXCLUDED.token,
+            created_at = NOW(),
+            refreshed_at = NOW(),
+            user_agent = EXCLUDED.user_agent,
+            ip = EXCLUDED.ip,
+            revoked_at = NULL;
+        "#,
         user_id,
+        device_id,
         token,
+        origin.user_agent,
+        origin.ip,
     )
     .execute(&mut *db)
     .await
 }
 
+pub async fn get_session_device(
+    db: &mut PgConnection,
+    user_id: Uuid,
+    token: &str,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r"SELECT device_id FROM sessions WHERE user_id = $1 AND token = $2 AND revoked_at IS NULL;",
+        user_id,
+        token
+    )
+    .fetch_optional(&mut *db)
+    .await
+}
+
 pub async fn update_session(
     db: &mut PgConnection,
     user_id: Uuid,
     old_token: &str,
     new_token: &str,
+    origin: &ClientOrigin,
 ) -> Result<PgQueryResult, sqlx::Error> {
     sqlx::query!(
         r#"
         UPDATE sessions
         SET
             token = $3,
-            created_at = NOW()
-        WHERE user_id = $1 AND token = $2
+            refreshed_at = NOW(),
+            user_agent = $4,
+            ip = $5
+        WHERE user_id = $1 AND token = $2 AND revoked_at IS NULL
         "#,
         user_id,
         old_token,
-        new_token
+        new_token,
+        origin.user_agent,
+        origin.ip
     )
     .execute(&mut *db)
     .await
@@ -105,3 +205,426 @@ pub async fn delete_session(
     .execute(&mut *db)
     .await
 }
+
+pub async fn get_sessions(
+    db: &mut PgConnection,
+    user_id: Uuid,
+    current_device_id: Uuid,
+) -> Result<Vec<Session>, sqlx::Error> {
+    sqlx::query_as!(
+        Session,
+        r#"
+        SELECT
+            s.id,
+            s.device_id,
+            d.name AS device_name,
+            s.created_at,
+            s.refreshed_at,
+            s.user_agent,
+            s.ip,
+            s.device_id = $2 AS "current!"
+        FROM sessions s
+        JOIN devices d ON d.id = s.device_id
+        WHERE s.user_id = $1 AND s.revoked_at IS NULL
+        ORDER BY s.refreshed_at DESC, s.id DESC;
+        "#,
+        user_id,
+        current_device_id
+    )
+    .fetch_all(&mut *db)
+    .await
+}
+
+/// Revokes one session by id. The device it was on has to sign in again
+/// once its access token expires.
+pub async fn revoke_session(
+    db: &mut PgConnection,
+    user_id: Uuid,
+    current_device_id: Uuid,
+    id: i32,
+) -> Result<Option<Session>, sqlx::Error> {
+    sqlx::query_as!(
+        Session,
+        r#"
+        WITH s AS (
+            UPDATE sessions
+            SET revoked_at = NOW()
+            WHERE id = $3 AND user_id = $1 AND revoked_at IS NULL
+            RETURNING *
+        )
+        SELECT
+            s.id,
+            s.device_id,
+            d.name AS device_name,
+            s.created_at,
+            s.refreshed_at,
+            s.user_agent,
+            s.ip,
+            s.device_id = $2 AS "current!"
+        FROM s
+        JOIN devices d ON d.id = s.device_id;
+        "#,
+        user_id,
+        current_device_id,
+        id
+    )
+    .fetch_optional(&mut *db)
+    .await
+}
+
+/// Revokes every session of the user but the one of `current_device_id`.
+pub async fn revoke_other_sessions(
+    db: &mut PgConnection,
+    user_id: Uuid,
+    current_device_id: Uuid,
+) -> Result<Vec<Session>, sqlx::Error> {
+    sqlx::query_as!(
+        Session,
+        r#"
+        WITH s AS (
+            UPDATE sessions
+            SET revoked_at = NOW()
+            WHERE user_id = $1 AND device_id <> $2 AND revoked_at IS NULL
+            RETURNING *
+        )
+        SELECT
+            s.id,
+            s.device_id,
+            d.name AS device_name,
+            s.created_at,
+            s.refreshed_at,
+            s.user_agent,
+            s.ip,
+            false AS "current!"
+        FROM s
+        JOIN devices d ON d.id = s.device_id
+        ORDER BY s.refreshed_at DESC, s.id DESC;
+        "#,
+        user_id,
+        current_device_id
+    )
+    .fetch_all(&mut *db)
+    .await
+}
+
+pub async fn get_totp(db: &mut PgConnection, user_id: Uuid) -> Result<Option<Totp>, sqlx::Error> {
+    sqlx::query_as!(
+        Totp,
+        r#"
+        SELECT secret, confirmed_at, COALESCE(locked_until > NOW(), false) AS "locked!"
+        FROM totp_secrets
+        WHERE user_id = $1;
+        "#,
+        user_id
+    )
+    .fetch_optional(&mut *db)
+    .await
+}
+
+/// Stores a secret awaiting confirmation, replacing any earlier unconfirmed
+/// one. Returns false when 2FA is already enabled.
+pub async fn upsert_pending_totp(
+    db: &mut PgConnection,
+    user_id: Uuid,
+    secret: &[u8],
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO totp_secrets (user_id, secret)
+        VALUES ($1, $2)
+        ON CONFLICT (user_id) DO UPDATE
+        SET
+            secret = EXCLUDED.secret,
+            last_used_step = NULL,
+            created_at = NOW()
+        WHERE totp_secrets.confirmed_at IS NULL;
+        "#,
+        user_id,
+        secret
+    )
+    .execute(&mut *db)
+    .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+/// Enables 2FA with the pending secret, which `step` was verified against,
+/// and replaces the user's recovery codes.
+pub async fn confirm_totp(
+    db: &mut PgConnection,
+    user_id: Uuid,
+    step: i64,
+    recovery_code_hashes: &[Vec<u8>],
+) -> Result<bool, sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    let result = sqlx::query!(
+        r#"
+        UPDATE totp_secrets
+        SET
+            confirmed_at = NOW(),
+            last_used_step = $2
+        WHERE user_id = $1 AND confirmed_at IS NULL;
+        "#,
+        user_id,
+        step
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    replace_recovery_codes(&mut tx, user_id, recovery_code_hashes).await?;
+
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+/// Drops every recovery code of the user, used or not, for a new set.
+pub async fn replace_recovery_codes(
+    db: &mut PgConnection,
+    user_id: Uuid,
+    recovery_code_hashes: &[Vec<u8>],
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(r"DELETE FROM recovery_codes WHERE user_id = $1;", user_id)
+        .execute(&mut *db)
+        .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO recovery_codes (user_id, code_hash)
+        SELECT $1, unnest($2::bytea[]);
+        "#,
+        user_id,
+        recovery_code_hashes
+    )
+    .execute(&mut *db)
+    .await?;
+
+    Ok(())
+}
+
+/// Records `step` as used, unless it or a later one already was.
+pub async fn use_totp_step(
+    db: &mut PgConnection,
+    user_id: Uuid,
+    step: i64,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE totp_secrets
+        SET last_used_step = $2
+        WHERE user_id = $1 AND confirmed_at IS NOT NULL
+            AND (last_used_step IS NULL OR last_used_step < $2);
+        "#,
+        user_id,
+        step
+    )
+    .execute(&mut *db)
+    .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+/// Counts a second factor check, locking the user's codes out once
+/// `MAX_TOTP_ATTEMPTS` fail in a row. A success resets the count.
+pub async fn record_totp_attempt(
+    db: &mut PgConnection,
+    user_id: Uuid,
+    succeeded: bool,
+) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE totp_secrets
+        SET
+            failed_attempts = CASE
+                WHEN $2 OR failed_attempts + 1 >= $3 THEN 0
+                ELSE failed_attempts + 1
+            END,
+            locked_until = CASE
+                WHEN NOT $2 AND failed_attempts + 1 >= $3
+                    THEN NOW() + make_interval(secs => $4)
+                ELSE locked_until
+            END
+        WHERE user_id = $1;
+        "#,
+        user_id,
+        succeeded,
+        MAX_TOTP_ATTEMPTS,
+        TOTP_LOCKOUT_SEC as f64
+    )
+    .execute(&mut *db)
+    .await
+}
+
+pub async fn use_recovery_code(
+    db: &mut PgConnection,
+    user_id: Uuid,
+    code_hash: &[u8],
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE recovery_codes
+        SET used_at = NOW()
+        WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL;
+        "#,
+        user_id,
+        code_hash
+    )
+    .execute(&mut *db)
+    .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+/// Whether the user holds this recovery code, unused, without spending it.
+pub async fn has_recovery_code(
+    db: &mut PgConnection,
+    user_id: Uuid,
+    code_hash: &[u8],
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS(
+            SELECT 1 FROM recovery_codes
+            WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL
+        ) AS "exists!";
+        "#,
+        user_id,
+        code_hash
+    )
+    .fetch_one(&mut *db)
+    .await
+}
+
+/// Turns 2FA off. Recovery codes stay, as they also reset the password.
+pub async fn delete_totp(db: &mut PgConnection, user_id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query!(r"DELETE FROM totp_secrets WHERE user_id = $1;", user_id)
+        .execute(&mut *db)
+        .await?;
+
+    Ok(())
+}
+
+/// Sets a new password hash and revokes every session but the one of
+/// `keep_device_id`, if any. Pending signin challenges are dropped too.
+pub async fn update_password(
+    db: &mut PgConnection,
+    user_id: Uuid,
+    password_hash: &str,
+    keep_device_id: Option<Uuid>,
+) -> Result<(), sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    sqlx::query!(
+        r"UPDATE users SET password = $2 WHERE id = $1;",
+        user_id,
+        password_hash
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        UPDATE sessions
+        SET revoked_at = NOW()
+        WHERE user_id = $1 AND revoked_at IS NULL
+            AND device_id IS DISTINCT FROM $2;
+        "#,
+        user_id,
+        keep_device_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        r"DELETE FROM signin_challenges WHERE user_id = $1;",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await
+}
+
+/// Spends a recovery code and resets the password with it, signing the user
+/// out everywhere. Returns false when the code is wrong or already used.
+pub async fn recover_account(
+    db: &mut PgConnection,
+    user_id: Uuid,
+    code_hash: &[u8],
+    password_hash: &str,
+) -> Result<bool, sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    if !use_recovery_code(&mut tx, user_id, code_hash).await? {
+        return Ok(false);
+    }
+
+    update_password(&mut tx, user_id, password_hash, None).await?;
+
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+/// Also clears out challenges that expired unanswered.
+pub async fn create_challenge(
+    db: &mut PgConnection,
+    token: &str,
+    user_id: Uuid,
+    device_id: Option<Uuid>,
+    device_name: Option<&str>,
+) -> Result<SecondFactorChallenge, sqlx::Error> {
+    sqlx::query!(r"DELETE FROM signin_challenges WHERE expires_at <= NOW();")
+        .execute(&mut *db)
+        .await?;
+
+    sqlx::query_as!(
+        SecondFactorChallenge,
+        r#"
+        INSERT INTO signin_challenges (token, user_id, device_id, device_name, expires_at)
+        VALUES ($1, $2, $3, $4, NOW() + make_interval(secs => $5))
+        RETURNING token AS challenge, expires_at;
+        "#,
+        token,
+        user_id,
+        device_id,
+        device_name,
+        CHALLENGE_TTL_SEC as f64
+    )
+    .fetch_one(&mut *db)
+    .await
+}
+
+/// Counts an attempt at answering the challenge. Expired challenges and
+/// ones out of attempts are not returned.
+pub async fn attempt_challenge(
+    db: &mut PgConnection,
+    token: &str,
+) -> Result<Option<Challenge>, sqlx::Error> {
+    sqlx::query_as!(
+        Challenge,
+        r#"
+        UPDATE signin_challenges
+        SET attempts = attempts + 1
+        WHERE token = $1 AND expires_at > NOW() AND attempts < $2
+        RETURNING user_id, device_id, device_name;
+        "#,
+        token,
+        MAX_CHALLENGE_ATTEMPTS
+    )
+    .fetch_optional(&mut *db)
+    .await
+}
+
+pub async fn delete_challenge(db: &mut PgConnection, token: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!(r"DELETE FROM signin_challenges WHERE token = $1;", token)
+        .execute(&mut *db)
+        .await?;
+
+    Ok(())
+}
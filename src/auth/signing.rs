@@ -0,0 +1,155 @@
+use super::Claims;
+use crate::config::{AccessTokenKey, Config, TokenAlgorithm};
+use data_encoding::BASE64URL_NOPAD;
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use jsonwebtoken::{
+    errors::{Error, ErrorKind},
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters,
+        EllipticCurveKeyType, Jwk, JwkSet, KeyAlgorithm, OctetKeyPairParameters, OctetKeyPairType,
+        PublicKeyUse,
+    },
+    Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use p256::{
+    elliptic_curve::sec1::ToEncodedPoint,
+    pkcs8::{DecodePrivateKey, DecodePublicKey},
+    PublicKey, SecretKey,
+};
+use std::collections::HashMap;
+
+/// Signs access tokens with the active key and verifies them with whichever
+/// configured key their `kid` names.
+pub struct TokenKeys {
+    header: Header,
+    encoding_key: EncodingKey,
+    decoding_keys: HashMap<Option<String>, (Algorithm, DecodingKey)>,
+    jwks: JwkSet,
+}
+
+impl TokenKeys {
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        if config.access_token_keys.is_empty() {
+            let secret = config.access_token_secret.as_bytes();
+
+            return Ok(TokenKeys {
+                header: Header::default(),
+                encoding_key: EncodingKey::from_secret(secret),
+                decoding_keys: HashMap::from([(
+                    None,
+                    (Algorithm::HS256, DecodingKey::from_secret(secret)),
+                )]),
+                jwks: JwkSet { keys: Vec::new() },
+            });
+        }
+
+        let mut active_keys = config.access_token_keys.iter().filter(|k| !k.retired);
+        let (Some(active), None) = (active_keys.next(), active_keys.next()) else {
+            return Err("exactly one access token key must not be retired".to_string());
+        };
+
+        let mut decoding_keys = HashMap::new();
+        let mut jwks = JwkSet { keys: Vec::new() };
+
+        for key in &config.access_token_keys {
+            let jwk = public_jwk(key)?;
+            let decoding_key = DecodingKey::from_jwk(&jwk)
+                .map_err(|e| format!("access token key {}: {}", key.kid, e))?;
+
+            let entry = (algorithm(key.algorithm), decoding_key);
+            if decoding_keys.insert(Some(key.kid.clone()), entry).is_some() {
+                return Err(format!("access token key {} is listed twice", key.kid));
+            }
+
+            jwks.keys.push(jwk);
+        }
+
+        let encoding_key = match active.algorithm {
+            TokenAlgorithm::EdDSA => EncodingKey::from_ed_pem(active.pem.as_bytes()),
+            TokenAlgorithm::ES256 => EncodingKey::from_ec_pem(active.pem.as_bytes()),
+        }
+        .map_err(|e| format!("access token key {} can't sign: {}", active.kid, e))?;
+
+        let mut header = Header::new(algorithm(active.algorithm));
+        header.kid = Some(active.kid.clone());
+
+        Ok(TokenKeys {
+            header,
+            encoding_key,
+            decoding_keys,
+            jwks,
+        })
+    }
+
+    pub fn encode(&self, claims: &Claims) -> Result<String, Error> {
+        jsonwebtoken::encode(&self.header, claims, &self.encoding_key)
+    }
+
+    pub fn decode(&self, token: &str) -> Result<Claims, Error> {
+        let kid = jsonwebtoken::decode_header(token)?.kid;
+        let (algorithm, key) = self
+            .decoding_keys
+            .get(&kid)
+            .ok_or(Error::from(ErrorKind::InvalidSignature))?;
+
+        jsonwebtoken::decode(token, key, &Validation::new(*algorithm)).map(|d| d.claims)
+    }
+
+    /// Public halves of every configured key, retired ones included.
+    pub fn jwks(&self) -> &JwkSet {
+        &self.jwks
+    }
+}
+
+fn algorithm(algorithm: TokenAlgorithm) -> Algorithm {
+    match algorithm {
+        TokenAlgorithm::EdDSA => Algorithm::EdDSA,
+        TokenAlgorithm::ES256 => Algorithm::ES256,
+    }
+}
+
+/// The public key of `key` as a JWK, read off its private or public PEM.
+fn public_jwk(key: &AccessTokenKey) -> Result<Jwk, String> {
+    let (key_algorithm, parameters) = match key.algorithm {
+        TokenAlgorithm::EdDSA => {
+            let public_key = SigningKey::from_pkcs8_pem(&key.pem)
+                .map(|k| k.verifying_key())
+                .or_else(|_| VerifyingKey::from_public_key_pem(&key.pem))
+                .map_err(|_| format!("access token key {} is not an Ed25519 key", key.kid))?;
+
+            let parameters = AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                key_type: OctetKeyPairType::OctetKeyPair,
+                curve: EllipticCurve::Ed25519,
+                x: BASE64URL_NOPAD.encode(public_key.as_bytes()),
+            });
+
+            (KeyAlgorithm::EdDSA, parameters)
+        }
+        TokenAlgorithm::ES256 => {
+            let public_key = SecretKey::from_pkcs8_pem(&key.pem)
+                .map(|k| k.public_key())
+                .or_else(|_| PublicKey::from_public_key_pem(&key.pem))
+                .map_err(|_| format!("access token key {} is not a P-256 key", key.kid))?;
+            let point = public_key.to_encoded_point(false);
+
+            let parameters = AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+                key_type: EllipticCurveKeyType::EC,
+                curve: EllipticCurve::P256,
+                x: BASE64URL_NOPAD.encode(point.x().expect("point is uncompressed")),
+                y: BASE64URL_NOPAD.encode(point.y().expect("point is uncompressed")),
+            });
+
+            (KeyAlgorithm::ES256, parameters)
+        }
+    };
+
+    Ok(Jwk {
+        common: CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            key_algorithm: Some(key_algorithm),
+            key_id: Some(key.kid.clone()),
+            ..Default::default()
+        },
+        algorithm: parameters,
+    })
+}
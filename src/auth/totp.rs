@@ -0,0 +1,55 @@
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+const STEP_SEC: u64 = 30;
+const DIGITS: u32 = 6;
+const ISSUER: &str = "nanochat";
+
+/// Steps on either side of the current one whose codes are still accepted,
+/// to make up for clock drift.
+const SKEW: u64 = 1;
+
+pub fn generate_secret() -> Vec<u8> {
+    rand::random::<[u8; 20]>().to_vec()
+}
+
+pub fn encode_secret(secret: &[u8]) -> String {
+    BASE32_NOPAD.encode(secret)
+}
+
+/// The `otpauth://` URI authenticator apps scan as a QR code.
+pub fn provisioning_uri(secret: &[u8], username: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{username}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP_SEC}",
+        issuer = ISSUER,
+        secret = encode_secret(secret),
+    )
+}
+
+/// RFC 6238 code of `secret` for the time step `step`.
+fn code_at(secret: &[u8], step: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let truncated = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap());
+
+    (truncated & 0x7fff_ffff) % 10u32.pow(DIGITS)
+}
+
+/// The time step `code` is valid for at `unix_time`, if any. Callers must
+/// still reject steps at or before the last one accepted.
+pub fn verify(secret: &[u8], code: &str, unix_time: u64) -> Option<i64> {
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let code: u32 = code.parse().ok()?;
+    let current = unix_time / STEP_SEC;
+
+    (current.saturating_sub(SKEW)..=current + SKEW)
+        .find(|&step| code_at(secret, step) == code)
+        .map(|step| step as i64)
+}
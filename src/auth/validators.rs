@@ -17,3 +17,7 @@ pub fn is_valid_password(password: &str) -> bool {
         && ascii_digit_count > 0
         && other_count > 0
 }
+
+pub fn is_valid_device_name(name: &str) -> bool {
+    !name.trim().is_empty() && name.chars().count() <= 64
+}
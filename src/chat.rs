@@ -1,20 +1,42 @@
-use rocket::serde::{Deserialize, Serialize};
-use sqlx::types::Uuid;
+use crate::{users::option_hex, Validate};
+use rocket::serde::{Deserialize, Serialize, Serializer};
+use sqlx::types::{chrono::NaiveDateTime, Uuid};
+use std::{collections::HashSet, fmt, str::FromStr};
 
 pub mod handlers;
+pub mod purge;
 pub mod repo;
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 #[serde(crate = "rocket::serde")]
 #[serde(rename_all = "camelCase")]
 pub struct StoredMessage {
     pub id: i32,
+    pub chat_id: i32,
     pub sender_id: Uuid,
     pub recipient_id: Uuid,
 
     #[serde(with = "hex::serde")]
     pub content: Vec<u8>,
     pub created_at: sqlx::types::chrono::NaiveDateTime,
+    pub key_version: i32,
+    pub edit_count: i32,
+    pub edited_at: Option<sqlx::types::chrono::NaiveDateTime>,
+
+    /// Set once the sender deletes the message, which also empties `content`.
+    pub deleted_at: Option<sqlx::types::chrono::NaiveDateTime>,
+    pub attachment_id: Option<Uuid>,
+    pub ttl_sec: Option<i32>,
+
+    /// Past this point the message is never served and soon purged.
+    pub expires_at: Option<NaiveDateTime>,
+    pub client_id: Option<Uuid>,
+
+    /// Position in the chat, increasing by one with every message sent.
+    pub seq: i32,
+    pub reply_to: Option<i32>,
+    pub delivered: bool,
+    pub read: bool,
 }
 
 #[derive(Deserialize)]
@@ -25,4 +47,341 @@ pub struct CreatedMessage {
 
     #[serde(with = "hex::serde")]
     pub content: Vec<u8>,
+
+    /// Chat key version the content was encrypted under. Defaults to the
+    /// current one.
+    pub key_version: Option<i32>,
+
+    /// An attachment uploaded to the same chat.
+    pub attachment_id: Option<Uuid>,
+
+    /// Generated by the client and unique per sender, so retries are safe.
+    /// Reusing one for a different message is refused.
+    pub client_id: Option<Uuid>,
+
+    /// A message of the same chat this one replies to.
+    pub reply_to: Option<i32>,
+
+    /// The content encrypted once more for each device of either party.
+    #[serde(default)]
+    pub envelopes: Vec<Envelope>,
+}
+
+impl Validate for CreatedMessage {
+    fn validate(&self) -> bool {
+        are_valid_envelopes(&self.envelopes)
+    }
+}
+
+/// A stored message as returned to its sender, telling whether it was
+/// already stored by an earlier attempt.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SentMessage {
+    #[serde(flatten)]
+    pub message: StoredMessage,
+    pub replayed: bool,
+}
+
+impl SentMessage {
+    fn replayed(message: StoredMessage) -> Self {
+        SentMessage {
+            message,
+            replayed: true,
+        }
+    }
+}
+
+/// What became of a send.
+pub enum SendOutcome {
+    Sent(SentMessage),
+
+    /// The client id belongs to another message, or to one that is gone.
+    ClientIdReused,
+
+    /// The chat, key version, attachment, reply or envelopes don't allow it.
+    Refused,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct EditedMessage {
+    #[serde(with = "hex::serde")]
+    pub content: Vec<u8>,
+    pub key_version: Option<i32>,
+
+    /// Replaces every envelope of the original message.
+    #[serde(default)]
+    pub envelopes: Vec<Envelope>,
+}
+
+impl Validate for EditedMessage {
+    fn validate(&self) -> bool {
+        are_valid_envelopes(&self.envelopes)
+    }
+}
+
+fn are_valid_envelopes(envelopes: &[Envelope]) -> bool {
+    let mut device_ids = HashSet::new();
+
+    envelopes.len() <= 64 && envelopes.iter().all(|e| device_ids.insert(e.device_id))
+}
+
+/// An accepted chat as seen by one of its two members, whoever of them
+/// sent the invite.
+pub struct Membership {
+    pub chat_id: i32,
+    pub user_id: Uuid,
+    pub friend_id: Uuid,
+}
+
+/// What a chat-scoped request names its chat by.
+#[derive(Clone, Copy)]
+pub enum ChatRef {
+    Friend(Uuid),
+
+    /// A message of the chat that hasn't expired.
+    Message(i32),
+
+    /// An attachment of the chat not sent with an expired message.
+    Attachment(Uuid),
+}
+
+/// What a purge run removed. The attachment blobs are left to the caller.
+pub struct PurgedMessages {
+    pub messages: i64,
+    pub attachment_ids: Vec<Uuid>,
+}
+
+/// How far into a chat `user_id` has received and read. Every message
+/// with an id up to a mark counts as delivered or read.
+#[derive(Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct Receipt {
+    pub chat_id: i32,
+    pub user_id: Uuid,
+    pub delivered_up_to: i32,
+    pub read_up_to: i32,
+    pub updated_at: sqlx::types::chrono::NaiveDateTime,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct ReceiptUpdate {
+    pub delivered_up_to: Option<i32>,
+
+    /// Reading a message implies it was delivered.
+    pub read_up_to: Option<i32>,
+}
+
+impl Validate for ReceiptUpdate {
+    fn validate(&self) -> bool {
+        self.delivered_up_to.is_some() || self.read_up_to.is_some()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct Envelope {
+    pub device_id: Uuid,
+
+    #[serde(with = "hex::serde")]
+    pub content: Vec<u8>,
+}
+
+/// An entry of the conversation list, seen from the caller's side.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct ChatSummary {
+    pub id: i32,
+    pub friend_id: Uuid,
+    pub sender_id: Uuid,
+    pub recipient_id: Uuid,
+
+    #[serde(with = "hex::serde")]
+    pub sender_public_key: Vec<u8>,
+
+    #[serde(with = "option_hex")]
+    pub recipient_public_key: Option<Vec<u8>>,
+    pub key_version: i32,
+    pub message_ttl_sec: Option<i32>,
+    pub ttl_after_read: bool,
+    pub last_seq: i32,
+    pub last_activity_at: NaiveDateTime,
+    pub last_message: Option<StoredMessage>,
+
+    /// Messages received past the caller's read mark.
+    pub unread_count: i64,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct ChatList {
+    pub chats: Vec<ChatSummary>,
+    pub next_cursor: Option<Cursor>,
+}
+
+/// A message as listed in history, with its reactions grouped by key and
+/// how many replies it has.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct ListedMessage {
+    #[serde(flatten)]
+    pub message: StoredMessage,
+    pub reactions: Vec<ReactionGroup>,
+    pub reply_count: i64,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct Reaction {
+    pub message_id: i32,
+    pub user_id: Uuid,
+
+    #[serde(with = "hex::serde")]
+    pub reaction_key: Vec<u8>,
+
+    #[serde(with = "hex::serde")]
+    pub payload: Vec<u8>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct ReactionGroup {
+    #[serde(with = "hex::serde")]
+    pub reaction_key: Vec<u8>,
+    pub count: usize,
+    pub reactions: Vec<Reaction>,
+}
+
+impl ReactionGroup {
+    /// Groups reactions already sorted by key.
+    pub fn group(reactions: Vec<Reaction>) -> Vec<Self> {
+        let mut groups: Vec<ReactionGroup> = Vec::new();
+
+        for reaction in reactions {
+            match groups.last_mut() {
+                Some(g) if g.reaction_key == reaction.reaction_key => {
+                    g.count += 1;
+                    g.reactions.push(reaction);
+                }
+                _ => groups.push(ReactionGroup {
+                    reaction_key: reaction.reaction_key.clone(),
+                    count: 1,
+                    reactions: vec![reaction],
+                }),
+            }
+        }
+
+        groups
+    }
+}
+
+/// Every reaction a message has after one was added or removed.
+#[derive(Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct MessageReactions {
+    pub message_id: i32,
+    pub chat_id: i32,
+    pub reactions: Vec<ReactionGroup>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct NewReaction {
+    /// Opaque to the server, typically encrypted for the chat.
+    #[serde(with = "hex::serde")]
+    pub payload: Vec<u8>,
+}
+
+impl Validate for NewReaction {
+    fn validate(&self) -> bool {
+        self.payload.len() <= 256
+    }
+}
+
+/// A page of a conversation, newest first. `next_cursor` leads to older
+/// messages and `prev_cursor` to newer ones.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct MessageList {
+    pub messages: Vec<ListedMessage>,
+    pub next_cursor: Option<Cursor>,
+    pub prev_cursor: Option<Cursor>,
+}
+
+/// Messages a client missed since some sequence number. Once `has_more` is
+/// false, numbers still missing up to `last_seq` belong to expired messages.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct SyncPage {
+    pub messages: Vec<ListedMessage>,
+    pub last_seq: i32,
+    pub has_more: bool,
+}
+
+/// Opaque keyset position, made of a timestamp and the id that breaks ties.
+#[derive(Clone, Copy)]
+pub struct Cursor {
+    pub timestamp: NaiveDateTime,
+    pub id: i32,
+}
+
+impl From<&StoredMessage> for Cursor {
+    fn from(message: &StoredMessage) -> Self {
+        Cursor {
+            timestamp: message.created_at,
+            id: message.id,
+        }
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let position = format!(
+            "{}:{}",
+            self.timestamp.and_utc().timestamp_micros(),
+            self.id
+        );
+        f.write_str(&hex::encode(position))
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let position = String::from_utf8(hex::decode(s).or(Err(()))?).or(Err(()))?;
+        let (micros, id) = position.split_once(':').ok_or(())?;
+        let timestamp = chrono::DateTime::from_timestamp_micros(micros.parse().or(Err(()))?)
+            .ok_or(())?
+            .naive_utc();
+
+        Ok(Cursor {
+            timestamp,
+            id: id.parse().or(Err(()))?,
+        })
+    }
+}
+
+impl Serialize for Cursor {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
 }
@@ -1,20 +1,236 @@
-use crate::auth::AuthenticatedUser;
-use crate::{chat::CreatedMessage, db::Db};
-use rocket::{http::Status, serde::json::Json};
+use crate::auth::{AuthenticatedDevice, AuthenticatedUser};
+use crate::{
+    attachments::storage::{self, Storage},
+    chat::{CreatedMessage, EditedMessage},
+    db::Db,
+    Validate,
+};
+use rocket::{http::Status, serde::json::Json, FromForm, State};
 use rocket_db_pools::Connection;
+use std::sync::Arc;
 
-use super::{repo, StoredMessage};
+use super::{
+    repo, ChatList, ChatRef, Cursor, MessageList, NewReaction, Reaction, SendOutcome, SentMessage,
+    StoredMessage,
+};
+
+const DEFAULT_CHAT_PAGE: i64 = 20;
+const MAX_CHAT_PAGE: i64 = 100;
+const DEFAULT_REPLY_PAGE: i64 = 50;
+const MAX_REPLY_PAGE: i64 = 100;
 
 #[rocket::post("/", data = "<body>")]
 pub async fn insert_message(
     mut db: Connection<Db>,
     body: Json<CreatedMessage>,
+    device: AuthenticatedDevice,
+) -> Result<Json<SentMessage>, Status> {
+    if !body.validate() {
+        return Err(Status::UnprocessableEntity);
+    }
+
+    let membership =
+        repo::get_membership(&mut db, device.user.id, ChatRef::Friend(body.recipient_id))
+            .await
+            .or(Err(Status::InternalServerError))?
+            .ok_or(Status::Forbidden)?;
+
+    let outcome = repo::insert_message(&mut db, &membership, &body, device.id)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    match outcome {
+        SendOutcome::Sent(message) => Ok(Json(message)),
+        SendOutcome::ClientIdReused => Err(Status::Conflict),
+        SendOutcome::Refused => Err(Status::Forbidden),
+    }
+}
+
+#[rocket::patch("/<id>", data = "<body>")]
+pub async fn edit_message(
+    mut db: Connection<Db>,
+    body: Json<EditedMessage>,
+    id: i32,
+    sender: AuthenticatedUser,
+) -> Result<Json<StoredMessage>, Status> {
+    if !body.validate() {
+        return Err(Status::UnprocessableEntity);
+    }
+
+    repo::get_membership(&mut db, sender.id, ChatRef::Message(id))
+        .await
+        .or(Err(Status::InternalServerError))?
+        .ok_or(Status::NotFound)?;
+
+    let message = repo::edit_message(
+        &mut db,
+        id,
+        sender.id,
+        &body.content,
+        body.key_version,
+        &body.envelopes,
+    )
+    .await
+    .or(Err(Status::InternalServerError))?
+    .ok_or(Status::NotFound)?;
+
+    Ok(Json(message))
+}
+
+#[rocket::delete("/<id>")]
+pub async fn delete_message(
+    mut db: Connection<Db>,
+    id: i32,
     sender: AuthenticatedUser,
+    storage: &State<Arc<dyn Storage>>,
 ) -> Result<Json<StoredMessage>, Status> {
-    let message = repo::insert_message(&mut db, sender.id, body.recipient_id, &body.content)
+    repo::get_membership(&mut db, sender.id, ChatRef::Message(id))
+        .await
+        .or(Err(Status::InternalServerError))?
+        .ok_or(Status::NotFound)?;
+
+    let (message, attachment_id) = repo::delete_message(&mut db, id, sender.id)
         .await
         .or(Err(Status::InternalServerError))?
-        .ok_or(Status::Forbidden)?;
+        .ok_or(Status::NotFound)?;
+
+    storage::delete_all(storage, attachment_id.as_slice()).await;
 
     Ok(Json(message))
 }
+
+#[derive(FromForm)]
+pub struct ChatPage {
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[rocket::get("/?<page..>")]
+pub async fn get_chats(
+    mut db: Connection<Db>,
+    device: AuthenticatedDevice,
+    page: ChatPage,
+) -> Result<Json<ChatList>, Status> {
+    let limit = page
+        .limit
+        .unwrap_or(DEFAULT_CHAT_PAGE)
+        .clamp(1, MAX_CHAT_PAGE);
+    let cursor = page
+        .cursor
+        .map(|c| c.parse::<Cursor>())
+        .transpose()
+        .or(Err(Status::UnprocessableEntity))?;
+
+    let chats = repo::get_chats(&mut db, device.user.id, device.id, cursor, limit)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    let next_cursor = match chats.last() {
+        Some(c) if chats.len() as i64 == limit => Some(Cursor {
+            timestamp: c.last_activity_at,
+            id: c.id,
+        }),
+        _ => None,
+    };
+
+    Ok(Json(ChatList { chats, next_cursor }))
+}
+
+#[rocket::put("/<id>/reactions/<key>", data = "<body>")]
+pub async fn put_reaction(
+    mut db: Connection<Db>,
+    body: Json<NewReaction>,
+    id: i32,
+    key: &str,
+    user: AuthenticatedUser,
+) -> Result<Json<Reaction>, Status> {
+    let key = parse_reaction_key(key)?;
+
+    if !body.validate() {
+        return Err(Status::UnprocessableEntity);
+    }
+
+    repo::get_membership(&mut db, user.id, ChatRef::Message(id))
+        .await
+        .or(Err(Status::InternalServerError))?
+        .ok_or(Status::NotFound)?;
+
+    let reaction = repo::upsert_reaction(&mut db, id, user.id, &key, &body.payload)
+        .await
+        .or(Err(Status::InternalServerError))?
+        .ok_or(Status::NotFound)?;
+
+    Ok(Json(reaction))
+}
+
+#[rocket::delete("/<id>/reactions/<key>")]
+pub async fn delete_reaction(
+    mut db: Connection<Db>,
+    id: i32,
+    key: &str,
+    user: AuthenticatedUser,
+) -> Result<Json<Reaction>, Status> {
+    let key = parse_reaction_key(key)?;
+
+    repo::get_membership(&mut db, user.id, ChatRef::Message(id))
+        .await
+        .or(Err(Status::InternalServerError))?
+        .ok_or(Status::NotFound)?;
+
+    let reaction = repo::delete_reaction(&mut db, id, user.id, &key)
+        .await
+        .or(Err(Status::InternalServerError))?
+        .ok_or(Status::NotFound)?;
+
+    Ok(Json(reaction))
+}
+
+/// Reaction keys travel hex encoded in the path and hold up to 32 bytes.
+fn parse_reaction_key(key: &str) -> Result<Vec<u8>, Status> {
+    hex::decode(key)
+        .ok()
+        .filter(|k| (1..=32).contains(&k.len()))
+        .ok_or(Status::UnprocessableEntity)
+}
+
+#[rocket::get("/<id>/replies?<before>&<limit>")]
+pub async fn get_replies(
+    mut db: Connection<Db>,
+    id: i32,
+    before: Option<String>,
+    limit: Option<i64>,
+    device: AuthenticatedDevice,
+) -> Result<Json<MessageList>, Status> {
+    let limit = limit.unwrap_or(DEFAULT_REPLY_PAGE).clamp(1, MAX_REPLY_PAGE);
+    let before = before
+        .map(|c| c.parse::<Cursor>())
+        .transpose()
+        .or(Err(Status::UnprocessableEntity))?;
+
+    repo::get_membership(&mut db, device.user.id, ChatRef::Message(id))
+        .await
+        .or(Err(Status::InternalServerError))?
+        .ok_or(Status::NotFound)?;
+
+    let mut replies = repo::get_replies(&mut db, id, device.id, before, limit + 1)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    let more = replies.len() as i64 > limit;
+    replies.truncate(limit as usize);
+    let next_cursor = replies.last().filter(|_| more).map(Cursor::from);
+    let prev_cursor = replies
+        .first()
+        .filter(|_| before.is_some())
+        .map(Cursor::from);
+
+    let messages = repo::annotate_messages(&mut db, replies)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    Ok(Json(MessageList {
+        messages,
+        next_cursor,
+        prev_cursor,
+    }))
+}
@@ -0,0 +1,68 @@
+use super::repo;
+use crate::{
+    attachments::storage::{self, Storage},
+    config::Config,
+    db::Db,
+};
+use rocket::{fairing::AdHoc, tokio::time};
+use rocket_db_pools::Database;
+use sqlx::PgPool;
+use std::{sync::Arc, time::Duration};
+
+/// Spawns a task that hard-deletes expired messages and their attachments
+/// every `purge_interval_sec`. Reads already skip them in the meantime.
+/// Uploads never sent with a message are deleted the same way once older
+/// than `attachment_unreferenced_ttl_sec`.
+pub fn fairing() -> AdHoc {
+    AdHoc::on_liftoff("Message Purge", |rocket| {
+        Box::pin(async move {
+            let pool = PgPool::clone(Db::fetch(rocket).expect("database is attached"));
+            let storage = Arc::clone(
+                rocket
+                    .state::<Arc<dyn Storage>>()
+                    .expect("storage is attached"),
+            );
+            let config = rocket.state::<Config>().expect("config is attached");
+
+            rocket::tokio::spawn(purge(
+                pool,
+                storage,
+                Duration::from_secs(config.purge_interval_sec),
+                config.attachment_unreferenced_ttl_sec,
+            ));
+        })
+    })
+}
+
+async fn purge(
+    pool: PgPool,
+    storage: Arc<dyn Storage>,
+    period: Duration,
+    unreferenced_ttl_sec: u64,
+) {
+    let mut interval = time::interval(period);
+
+    loop {
+        interval.tick().await;
+
+        let result = match pool.acquire().await {
+            Ok(mut db) => repo::purge_expired(&mut db, unreferenced_ttl_sec).await,
+            Err(e) => Err(e),
+        };
+
+        let purged = match result {
+            Ok(p) => p,
+            Err(sqlx::Error::PoolClosed) => break,
+            Err(e) => {
+                rocket::error!("failed to purge expired messages: {}", e);
+                continue;
+            }
+        };
+
+        if purged.messages > 0 {
+            rocket::info!("purged {} expired messages", purged.messages);
+        }
+
+        storage::delete_all(&storage, &purged.attachment_ids).await;
+    }
+}
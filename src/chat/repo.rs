@@ -1,61 +1,955 @@
-use super::StoredMessage;
-use sqlx::{
-    types::{chrono::NaiveDateTime, Uuid},
-    PgConnection,
+use super::{
+    ChatRef, ChatSummary, CreatedMessage, Cursor, Envelope, ListedMessage, Membership,
+    MessageReactions, PurgedMessages, Reaction, ReactionGroup, Receipt, SendOutcome, SentMessage,
+    StoredMessage,
 };
+use crate::events::{self, Notification};
+use sqlx::{types::Uuid, Connection, PgConnection};
+use std::collections::HashMap;
 
-pub async fn get_message_page(
+pub async fn get_message(
     db: &mut PgConnection,
-    sender_id: Uuid,
-    recipient_id: Uuid,
-    start_timestamp: NaiveDateTime,
-    start_id: i32,
+    id: i32,
+) -> Result<Option<StoredMessage>, sqlx::Error> {
+    Ok(get_messages(db, &[id], None).await?.pop())
+}
+
+pub async fn get_envelopes(
+    db: &mut PgConnection,
+    message_id: i32,
+) -> Result<Vec<Envelope>, sqlx::Error> {
+    sqlx::query_as!(
+        Envelope,
+        r"SELECT device_id, content FROM message_envelopes WHERE message_id = $1;",
+        message_id
+    )
+    .fetch_all(&mut *db)
+    .await
+}
+
+/// Up to `limit` messages of the chat older than `before`, or the latest
+/// ones without a cursor, newest first.
+///
+/// Messages carry the envelope addressed to `device_id` as their content
+/// when there is one.
+pub async fn get_messages_before(
+    db: &mut PgConnection,
+    chat_id: i32,
+    device_id: Uuid,
+    before: Option<Cursor>,
     limit: i64,
 ) -> Result<Vec<StoredMessage>, sqlx::Error> {
-    sqlx::query_as!(
-        StoredMessage,
+    let ids = sqlx::query_scalar!(
         r#"
-        SELECT * FROM messages
-        WHERE
-            (sender_id = $1 AND recipient_id = $2) OR
-            (sender_id = $2 AND recipient_id = $1) AND
-            (created_at, id) < ($3, $4)
-        LIMIT $5;
+        SELECT m.id
+        FROM messages m
+        WHERE m.chat_id = $1
+            AND (m.created_at, m.id) < (coalesce($2, 'infinity'::timestamp), $3)
+            AND (m.expires_at IS NULL OR m.expires_at > now())
+        ORDER BY m.created_at DESC, m.id DESC
+        LIMIT $4;
         "#,
-        sender_id,
-        recipient_id,
-        start_timestamp,
-        start_id,
+        chat_id,
+        before.map(|c| c.timestamp),
+        before.map_or(i32::MAX, |c| c.id),
         limit
     )
     .fetch_all(&mut *db)
-    .await
+    .await?;
+
+    get_messages(db, &ids, Some(device_id)).await
+}
+
+/// Up to `limit` messages of the chat newer than `after`, oldest first.
+pub async fn get_messages_after(
+    db: &mut PgConnection,
+    chat_id: i32,
+    device_id: Uuid,
+    after: Cursor,
+    limit: i64,
+) -> Result<Vec<StoredMessage>, sqlx::Error> {
+    let ids = sqlx::query_scalar!(
+        r#"
+        SELECT m.id
+        FROM messages m
+        WHERE m.chat_id = $1 AND (m.created_at, m.id) > ($2, $3)
+            AND (m.expires_at IS NULL OR m.expires_at > now())
+        ORDER BY m.created_at, m.id
+        LIMIT $4;
+        "#,
+        chat_id,
+        after.timestamp,
+        after.id,
+        limit
+    )
+    .fetch_all(&mut *db)
+    .await?;
+
+    get_messages(db, &ids, Some(device_id)).await
+}
+
+/// Latest sequence number of the chat.
+pub async fn get_last_seq(db: &mut PgConnection, chat_id: i32) -> Result<i32, sqlx::Error> {
+    sqlx::query_scalar!(r"SELECT last_seq FROM chats WHERE id = $1;", chat_id)
+        .fetch_one(&mut *db)
+        .await
 }
 
+/// Up to `limit` messages of the chat with a sequence number past `since`,
+/// in sequence order.
+pub async fn get_messages_since(
+    db: &mut PgConnection,
+    chat_id: i32,
+    device_id: Uuid,
+    since: i32,
+    limit: i64,
+) -> Result<Vec<StoredMessage>, sqlx::Error> {
+    let ids = sqlx::query_scalar!(
+        r#"
+        SELECT m.id
+        FROM messages m
+        WHERE m.chat_id = $1 AND m.seq > $2
+            AND (m.expires_at IS NULL OR m.expires_at > now())
+        ORDER BY m.seq
+        LIMIT $3;
+        "#,
+        chat_id,
+        since,
+        limit
+    )
+    .fetch_all(&mut *db)
+    .await?;
+
+    get_messages(db, &ids, Some(device_id)).await
+}
+
+/// Sends as `membership.user_id` to the other member. A message whose
+/// `client_id` the sender already used is not stored again: a retry of the
+/// same send gets the original back flagged as a replay, anything else is
+/// refused. The message comes back as `device_id` reads it.
 pub async fn insert_message(
     db: &mut PgConnection,
+    membership: &Membership,
+    created: &CreatedMessage,
+    device_id: Uuid,
+) -> Result<SendOutcome, sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    if let Some(client_id) = created.client_id {
+        if let Some(outcome) =
+            find_replay(&mut tx, membership, created, client_id, device_id).await?
+        {
+            return Ok(outcome);
+        }
+    }
+
+    // A savepoint, so a send that isn't stored takes back its sequence number.
+    let mut insert = tx.begin().await?;
+
+    let id = sqlx::query_scalar!(
+        r#"
+        WITH c AS (
+            UPDATE chats
+            SET last_seq = last_seq + 1
+            WHERE id = $8
+                AND NOT is_blocked($1, $2)
+                AND ($4::integer IS NULL OR $4 BETWEEN 1 AND key_version)
+                AND ($5::uuid IS NULL OR EXISTS(
+                    SELECT 1 FROM attachments WHERE id = $5 AND chat_id = chats.id
+                ))
+                AND ($7::integer IS NULL OR EXISTS(
+                    SELECT 1 FROM messages WHERE id = $7 AND chat_id = chats.id
+                ))
+            RETURNING id, key_version, message_ttl_sec, ttl_after_read, last_seq
+        )
+        INSERT INTO messages (
+            chat_id,
+            sender_id,
+            recipient_id,
+            content,
+            key_version,
+            attachment_id,
+            ttl_sec,
+            expires_at,
+            client_id,
+            content_hash,
+            seq,
+            reply_to
+        )
+        SELECT
+            c.id,
+            $1,
+            $2,
+            $3,
+            coalesce($4, c.key_version),
+            $5,
+            c.message_ttl_sec,
+            CASE
+                WHEN NOT c.ttl_after_read THEN now() + make_interval(secs => c.message_ttl_sec)
+            END,
+            $6,
+            CASE WHEN $6::uuid IS NOT NULL THEN sha256($3) END,
+            c.last_seq,
+            $7
+        FROM c
+        ON CONFLICT (sender_id, client_id) DO NOTHING
+        RETURNING id;
+        "#,
+        membership.user_id,
+        membership.friend_id,
+        created.content,
+        created.key_version,
+        created.attachment_id,
+        created.client_id,
+        created.reply_to,
+        membership.chat_id
+    )
+    .fetch_optional(&mut *insert)
+    .await?;
+
+    let Some(id) = id else {
+        drop(insert);
+
+        // Either the send is not allowed, or a concurrent retry won the race.
+        let replay = match created.client_id {
+            Some(client_id) => {
+                find_replay(&mut tx, membership, created, client_id, device_id).await?
+            }
+            None => None,
+        };
+
+        return Ok(replay.unwrap_or(SendOutcome::Refused));
+    };
+
+    if !insert_envelopes(&mut insert, id, &created.envelopes).await? {
+        return Ok(SendOutcome::Refused);
+    }
+
+    events::repo::notify(&mut insert, &Notification::Message { id }).await?;
+    insert.commit().await?;
+
+    let message = read_back(&mut tx, id, Some(device_id)).await?;
+    tx.commit().await?;
+
+    Ok(SendOutcome::Sent(SentMessage {
+        message,
+        replayed: false,
+    }))
+}
+
+/// What a send reusing `client_id` comes to, if the sender used it before:
+/// the original message when it went to the same chat with the same
+/// content and still exists, a refusal otherwise.
+async fn find_replay(
+    db: &mut PgConnection,
+    membership: &Membership,
+    created: &CreatedMessage,
+    client_id: Uuid,
+    device_id: Uuid,
+) -> Result<Option<SendOutcome>, sqlx::Error> {
+    let original = sqlx::query!(
+        r#"
+        SELECT
+            id,
+            coalesce(
+                chat_id = $3 AND content_hash = sha256($4) AND deleted_at IS NULL
+                    AND (expires_at IS NULL OR expires_at > now()),
+                false
+            ) AS "matches!"
+        FROM messages
+        WHERE sender_id = $1 AND client_id = $2;
+        "#,
+        membership.user_id,
+        client_id,
+        membership.chat_id,
+        created.content
+    )
+    .fetch_optional(&mut *db)
+    .await?;
+
+    match original {
+        Some(o) if o.matches => {
+            let message = read_back(db, o.id, Some(device_id)).await?;
+            Ok(Some(SendOutcome::Sent(SentMessage::replayed(message))))
+        }
+        Some(_) => Ok(Some(SendOutcome::ClientIdReused)),
+        None => Ok(None),
+    }
+}
+
+/// Only the sender may edit, and deleted messages stay deleted.
+pub async fn edit_message(
+    db: &mut PgConnection,
+    id: i32,
     sender_id: Uuid,
-    recipient_id: Uuid,
-    content: &Vec<u8>,
+    content: &[u8],
+    key_version: Option<i32>,
+    envelopes: &[Envelope],
 ) -> Result<Option<StoredMessage>, sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    let updated = sqlx::query_scalar!(
+        r#"
+        UPDATE messages m
+        SET
+            content = $3,
+            key_version = coalesce($4, c.key_version),
+            edit_count = m.edit_count + 1,
+            edited_at = NOW()
+        FROM chats c
+        WHERE m.id = $1 AND m.sender_id = $2 AND m.deleted_at IS NULL AND c.id = m.chat_id
+            AND (m.expires_at IS NULL OR m.expires_at > now())
+            AND ($4::integer IS NULL OR $4 BETWEEN 1 AND c.key_version)
+        RETURNING m.id;
+        "#,
+        id,
+        sender_id,
+        content,
+        key_version
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    if updated.is_none() {
+        return Ok(None);
+    }
+
+    sqlx::query!(r"DELETE FROM message_envelopes WHERE message_id = $1;", id)
+        .execute(&mut *tx)
+        .await?;
+
+    if !insert_envelopes(&mut tx, id, envelopes).await? {
+        return Ok(None);
+    }
+
+    events::repo::notify(&mut tx, &Notification::MessageEdited { id }).await?;
+
+    let message = read_back(&mut tx, id, None).await?;
+    tx.commit().await?;
+
+    Ok(Some(message))
+}
+
+/// Leaves a tombstone in place of the message: its row stays so history
+/// keeps its shape, but content, envelopes, reactions and attachment are
+/// gone. Replies are messages of their own and stay, still counted on the
+/// tombstone and listed under it. Returns the id of the attachment row
+/// deleted with it, whose blob is left to the caller.
+pub async fn delete_message(
+    db: &mut PgConnection,
+    id: i32,
+    sender_id: Uuid,
+) -> Result<Option<(StoredMessage, Option<Uuid>)>, sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    let attachment_id = sqlx::query_scalar!(
+        r"SELECT attachment_id FROM messages WHERE id = $1 FOR UPDATE;",
+        id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .flatten();
+
+    let deleted = sqlx::query_scalar!(
+        r#"
+        UPDATE messages
+        SET
+            content = '',
+            attachment_id = NULL,
+            deleted_at = NOW()
+        WHERE id = $1 AND sender_id = $2 AND deleted_at IS NULL
+            AND (expires_at IS NULL OR expires_at > now())
+        RETURNING id;
+        "#,
+        id,
+        sender_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    if deleted.is_none() {
+        return Ok(None);
+    }
+
+    sqlx::query!(r"DELETE FROM message_envelopes WHERE message_id = $1;", id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query!(r"DELETE FROM message_reactions WHERE message_id = $1;", id)
+        .execute(&mut *tx)
+        .await?;
+
+    let deleted_attachment_id = sqlx::query_scalar!(
+        r#"
+        DELETE FROM attachments a
+        WHERE id = $1 AND NOT EXISTS(
+            SELECT 1
+            FROM messages
+            WHERE attachment_id = a.id AND (expires_at IS NULL OR expires_at > now())
+        )
+        RETURNING id;
+        "#,
+        attachment_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    events::repo::notify(&mut tx, &Notification::MessageDeleted { id }).await?;
+
+    let message = read_back(&mut tx, id, None).await?;
+    tx.commit().await?;
+
+    Ok(Some((message, deleted_attachment_id)))
+}
+
+/// Returns false when some envelope is addressed to a device outside the
+/// chat, in which case the caller must roll back.
+async fn insert_envelopes(
+    db: &mut PgConnection,
+    message_id: i32,
+    envelopes: &[Envelope],
+) -> Result<bool, sqlx::Error> {
+    let device_ids = envelopes.iter().map(|e| e.device_id).collect::<Vec<_>>();
+    let contents = envelopes
+        .iter()
+        .map(|e| e.content.clone())
+        .collect::<Vec<_>>();
+
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO message_envelopes (message_id, device_id, content)
+        SELECT m.id, e.device_id, e.content
+        FROM UNNEST($2::uuid[], $3::bytea[]) AS e(device_id, content)
+        JOIN messages m ON m.id = $1
+        JOIN devices d ON d.id = e.device_id
+        WHERE d.user_id IN (m.sender_id, m.recipient_id);
+        "#,
+        message_id,
+        &device_ids,
+        &contents
+    )
+    .execute(&mut *db)
+    .await?;
+
+    Ok(result.rows_affected() == envelopes.len() as u64)
+}
+
+pub async fn get_participant_ids(
+    db: &mut PgConnection,
+    chat_id: i32,
+) -> Result<Vec<Uuid>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT unnest(ARRAY[sender_id, recipient_id]) AS "user_id!"
+        FROM chats
+        WHERE id = $1;
+        "#,
+        chat_id
+    )
+    .fetch_all(&mut *db)
+    .await
+}
+
+pub async fn get_receipt(
+    db: &mut PgConnection,
+    chat_id: i32,
+    user_id: Uuid,
+) -> Result<Option<Receipt>, sqlx::Error> {
     sqlx::query_as!(
-        StoredMessage,
+        Receipt,
+        r"SELECT * FROM chat_receipts WHERE chat_id = $1 AND user_id = $2;",
+        chat_id,
+        user_id
+    )
+    .fetch_optional(&mut *db)
+    .await
+}
+
+pub async fn get_receipts(
+    db: &mut PgConnection,
+    chat_id: i32,
+) -> Result<Vec<Receipt>, sqlx::Error> {
+    sqlx::query_as!(
+        Receipt,
+        r"SELECT * FROM chat_receipts WHERE chat_id = $1;",
+        chat_id
+    )
+    .fetch_all(&mut *db)
+    .await
+}
+
+/// Moves the marks of `membership.user_id` forward, never back and never
+/// past the last message they received.
+pub async fn update_receipt(
+    db: &mut PgConnection,
+    membership: &Membership,
+    delivered_up_to: Option<i32>,
+    read_up_to: Option<i32>,
+) -> Result<Receipt, sqlx::Error> {
+    let user_id = membership.user_id;
+    let mut tx = db.begin().await?;
+
+    let receipt = sqlx::query_as!(
+        Receipt,
         r#"
-        INSERT INTO messages
-            (sender_id, recipient_id, content)
-        SELECT
-            $1, $2, $3
-        WHERE exists(
-            SELECT 1 FROM chats
-            WHERE sender_id = $1 AND recipient_id = $2
-                AND recipient_public_key IS NOT NULL
+        WITH latest AS (
+            SELECT coalesce(max(id), 0) AS message_id
+            FROM messages
+            WHERE chat_id = $2 AND recipient_id = $1
         )
+        INSERT INTO chat_receipts AS r (chat_id, user_id, delivered_up_to, read_up_to)
+        SELECT
+            $2,
+            $1,
+            least(greatest(coalesce($3, 0), coalesce($4, 0)), message_id),
+            least(coalesce($4, 0), message_id)
+        FROM latest
+        ON CONFLICT (chat_id, user_id) DO UPDATE
+        SET
+            delivered_up_to = greatest(
+                r.delivered_up_to,
+                EXCLUDED.delivered_up_to,
+                EXCLUDED.read_up_to
+            ),
+            read_up_to = greatest(r.read_up_to, EXCLUDED.read_up_to),
+            updated_at = NOW()
         RETURNING *;
         "#,
-        sender_id,
+        user_id,
+        membership.chat_id,
+        delivered_up_to,
+        read_up_to
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    start_read_timers(&mut tx, receipt.chat_id, user_id, receipt.read_up_to).await?;
+
+    events::repo::notify(
+        &mut tx,
+        &Notification::Receipt {
+            chat_id: receipt.chat_id,
+            user_id,
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(receipt)
+}
+
+/// Starts the clock on messages of chats that count their TTL from reading.
+async fn start_read_timers(
+    db: &mut PgConnection,
+    chat_id: i32,
+    recipient_id: Uuid,
+    read_up_to: i32,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE messages
+        SET expires_at = now() + make_interval(secs => ttl_sec)
+        WHERE chat_id = $1 AND recipient_id = $2 AND id <= $3
+            AND ttl_sec IS NOT NULL AND expires_at IS NULL;
+        "#,
+        chat_id,
         recipient_id,
-        content,
+        read_up_to
+    )
+    .execute(&mut *db)
+    .await?;
+
+    Ok(())
+}
+
+/// Hard-deletes every message past its expiry, along with the attachments
+/// no unexpired message still references. Attachments no message
+/// references go too once older than `unreferenced_ttl_sec`.
+pub async fn purge_expired(
+    db: &mut PgConnection,
+    unreferenced_ttl_sec: u64,
+) -> Result<PurgedMessages, sqlx::Error> {
+    sqlx::query_as!(
+        PurgedMessages,
+        r#"
+        WITH m AS (
+            DELETE FROM messages
+            WHERE expires_at <= now()
+            RETURNING attachment_id
+        ), a AS (
+            DELETE FROM attachments a
+            WHERE (
+                id IN (SELECT attachment_id FROM m)
+                OR created_at <= now() - make_interval(secs => $1)
+            ) AND NOT EXISTS(
+                SELECT 1
+                FROM messages l
+                WHERE l.attachment_id = a.id AND (l.expires_at IS NULL OR l.expires_at > now())
+            )
+            RETURNING id
+        )
+        SELECT
+            (SELECT count(*) FROM m) AS "messages!",
+            ARRAY(SELECT id FROM a) AS "attachment_ids!";
+        "#,
+        unreferenced_ttl_sec as f64
+    )
+    .fetch_one(&mut *db)
+    .await
+}
+
+/// Chats `user_id` takes part in, most recently active first.
+pub async fn get_chats(
+    db: &mut PgConnection,
+    user_id: Uuid,
+    device_id: Uuid,
+    before: Option<Cursor>,
+    limit: i64,
+) -> Result<Vec<ChatSummary>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT
+            c.id,
+            cm.friend_id AS "friend_id!",
+            c.sender_id,
+            c.recipient_id,
+            c.sender_public_key,
+            c.recipient_public_key,
+            c.key_version,
+            c.message_ttl_sec,
+            c.ttl_after_read,
+            c.last_seq,
+            l.id AS "last_message_id?",
+            coalesce(l.created_at, c.created_at) AS "last_activity_at!",
+            (
+                SELECT count(*)
+                FROM messages u
+                LEFT JOIN chat_receipts r ON r.chat_id = u.chat_id AND r.user_id = $1
+                WHERE u.chat_id = c.id AND u.recipient_id = $1 AND u.deleted_at IS NULL
+                    AND u.id > coalesce(r.read_up_to, 0)
+                    AND (u.expires_at IS NULL OR u.expires_at > now())
+            ) AS "unread_count!"
+        FROM chat_members cm
+        JOIN chats c ON c.id = cm.chat_id
+        LEFT JOIN LATERAL (
+            SELECT id, created_at
+            FROM messages
+            WHERE chat_id = c.id AND (expires_at IS NULL OR expires_at > now())
+            ORDER BY id DESC
+            LIMIT 1
+        ) l ON true
+        WHERE cm.user_id = $1
+            AND (
+                $2::timestamp IS NULL OR
+                (coalesce(l.created_at, c.created_at), c.id) < ($2, $3)
+            )
+        ORDER BY coalesce(l.created_at, c.created_at) DESC, c.id DESC
+        LIMIT $4;
+        "#,
+        user_id,
+        before.map(|c| c.timestamp),
+        before.map_or(0, |c| c.id),
+        limit
+    )
+    .fetch_all(&mut *db)
+    .await?;
+
+    let message_ids: Vec<i32> = rows.iter().filter_map(|r| r.last_message_id).collect();
+    let mut messages = get_messages(db, &message_ids, Some(device_id)).await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| ChatSummary {
+            id: r.id,
+            friend_id: r.friend_id,
+            sender_id: r.sender_id,
+            recipient_id: r.recipient_id,
+            sender_public_key: r.sender_public_key,
+            recipient_public_key: r.recipient_public_key,
+            key_version: r.key_version,
+            message_ttl_sec: r.message_ttl_sec,
+            ttl_after_read: r.ttl_after_read,
+            last_seq: r.last_seq,
+            last_activity_at: r.last_activity_at,
+            last_message: r
+                .last_message_id
+                .and_then(|id| messages.iter().position(|m| m.id == id))
+                .map(|i| messages.swap_remove(i)),
+            unread_count: r.unread_count,
+        })
+        .collect())
+}
+
+/// The query every read of stored messages goes through. Messages come
+/// back in the order of `ids`, carrying the envelope addressed to
+/// `device_id` as their content when there is one.
+async fn get_messages(
+    db: &mut PgConnection,
+    ids: &[i32],
+    device_id: Option<Uuid>,
+) -> Result<Vec<StoredMessage>, sqlx::Error> {
+    sqlx::query_as!(
+        StoredMessage,
+        r#"
+        SELECT
+            m.id,
+            m.chat_id,
+            m.sender_id,
+            m.recipient_id,
+            coalesce(e.content, m.content) AS "content!",
+            m.created_at,
+            m.key_version,
+            m.edit_count,
+            m.edited_at,
+            m.deleted_at,
+            m.attachment_id,
+            m.ttl_sec,
+            m.expires_at,
+            m.client_id,
+            m.seq,
+            m.reply_to,
+            is_delivered(m) AS "delivered!",
+            is_read(m) AS "read!"
+        FROM messages m
+        LEFT JOIN message_envelopes e ON e.message_id = m.id AND e.device_id = $2
+        WHERE m.id = ANY($1)
+        ORDER BY array_position($1, m.id);
+        "#,
+        ids,
+        device_id
+    )
+    .fetch_all(&mut *db)
+    .await
+}
+
+/// A message written earlier in the same transaction, read the way
+/// `get_messages` reads it.
+async fn read_back(
+    db: &mut PgConnection,
+    id: i32,
+    device_id: Option<Uuid>,
+) -> Result<StoredMessage, sqlx::Error> {
+    get_messages(db, &[id], device_id)
+        .await?
+        .pop()
+        .ok_or(sqlx::Error::RowNotFound)
+}
+
+/// The accepted chat `user_id` takes part in that `chat` points to. Every
+/// chat-scoped operation is authorized through this.
+pub async fn get_membership(
+    db: &mut PgConnection,
+    user_id: Uuid,
+    chat: ChatRef,
+) -> Result<Option<Membership>, sqlx::Error> {
+    let (friend_id, message_id, attachment_id) = match chat {
+        ChatRef::Friend(id) => (Some(id), None, None),
+        ChatRef::Message(id) => (None, Some(id), None),
+        ChatRef::Attachment(id) => (None, None, Some(id)),
+    };
+
+    sqlx::query_as!(
+        Membership,
+        r#"
+        SELECT chat_id AS "chat_id!", user_id AS "user_id!", friend_id AS "friend_id!"
+        FROM chat_members
+        WHERE user_id = $1 AND accepted AND (
+            friend_id = $2 OR
+            chat_id = (
+                SELECT chat_id FROM messages
+                WHERE id = $3 AND (expires_at IS NULL OR expires_at > now())
+            ) OR
+            chat_id = (
+                SELECT chat_id FROM attachments a
+                WHERE id = $4 AND NOT EXISTS(
+                    SELECT 1 FROM messages
+                    WHERE attachment_id = a.id AND expires_at <= now()
+                )
+            )
+        );
+        "#,
+        user_id,
+        friend_id,
+        message_id,
+        attachment_id
+    )
+    .fetch_optional(&mut *db)
+    .await
+}
+
+/// Adds or replaces the caller's reaction under `reaction_key`, as long as
+/// the message is still around.
+pub async fn upsert_reaction(
+    db: &mut PgConnection,
+    message_id: i32,
+    user_id: Uuid,
+    reaction_key: &[u8],
+    payload: &[u8],
+) -> Result<Option<Reaction>, sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    let reaction = sqlx::query_as!(
+        Reaction,
+        r#"
+        INSERT INTO message_reactions (message_id, user_id, reaction_key, payload)
+        SELECT m.id, $2, $3, $4
+        FROM messages m
+        WHERE m.id = $1 AND m.deleted_at IS NULL
+            AND (m.expires_at IS NULL OR m.expires_at > now())
+        ON CONFLICT (message_id, user_id, reaction_key) DO UPDATE
+        SET
+            payload = EXCLUDED.payload,
+            created_at = now()
+        RETURNING *;
+        "#,
+        message_id,
+        user_id,
+        reaction_key,
+        payload
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    if reaction.is_some() {
+        events::repo::notify(&mut tx, &Notification::Reactions { message_id }).await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(reaction)
+}
+
+pub async fn delete_reaction(
+    db: &mut PgConnection,
+    message_id: i32,
+    user_id: Uuid,
+    reaction_key: &[u8],
+) -> Result<Option<Reaction>, sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    let reaction = sqlx::query_as!(
+        Reaction,
+        r#"
+        DELETE FROM message_reactions
+        WHERE message_id = $1 AND user_id = $2 AND reaction_key = $3
+        RETURNING *;
+        "#,
+        message_id,
+        user_id,
+        reaction_key
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    if reaction.is_some() {
+        events::repo::notify(&mut tx, &Notification::Reactions { message_id }).await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(reaction)
+}
+
+pub async fn get_reactions(
+    db: &mut PgConnection,
+    message_ids: &[i32],
+) -> Result<Vec<Reaction>, sqlx::Error> {
+    sqlx::query_as!(
+        Reaction,
+        r#"
+        SELECT * FROM message_reactions
+        WHERE message_id = ANY($1)
+        ORDER BY message_id, reaction_key, created_at;
+        "#,
+        message_ids
     )
-    .fetch_optional(db)
+    .fetch_all(&mut *db)
     .await
 }
+
+pub async fn get_message_reactions(
+    db: &mut PgConnection,
+    message_id: i32,
+) -> Result<Option<MessageReactions>, sqlx::Error> {
+    let Some(message) = get_message(db, message_id).await? else {
+        return Ok(None);
+    };
+
+    let reactions = get_reactions(db, &[message_id]).await?;
+
+    Ok(Some(MessageReactions {
+        message_id,
+        chat_id: message.chat_id,
+        reactions: ReactionGroup::group(reactions),
+    }))
+}
+
+/// Pairs every message with its grouped reactions and reply count.
+pub async fn annotate_messages(
+    db: &mut PgConnection,
+    messages: Vec<StoredMessage>,
+) -> Result<Vec<ListedMessage>, sqlx::Error> {
+    let ids: Vec<i32> = messages.iter().map(|m| m.id).collect();
+    let mut reactions = HashMap::<i32, Vec<Reaction>>::new();
+
+    for reaction in get_reactions(db, &ids).await? {
+        reactions
+            .entry(reaction.message_id)
+            .or_default()
+            .push(reaction);
+    }
+
+    let reply_counts: HashMap<i32, i64> = sqlx::query!(
+        r#"
+        SELECT reply_to AS "id!", count(*) AS "count!"
+        FROM messages
+        WHERE reply_to = ANY($1) AND (expires_at IS NULL OR expires_at > now())
+        GROUP BY reply_to;
+        "#,
+        &ids
+    )
+    .fetch_all(&mut *db)
+    .await?
+    .into_iter()
+    .map(|r| (r.id, r.count))
+    .collect();
+
+    Ok(messages
+        .into_iter()
+        .map(|message| ListedMessage {
+            reactions: ReactionGroup::group(reactions.remove(&message.id).unwrap_or_default()),
+            reply_count: reply_counts.get(&message.id).copied().unwrap_or(0),
+            message,
+        })
+        .collect())
+}
+
+/// Up to `limit` replies to `parent_id` older than `before`, newest first.
+pub async fn get_replies(
+    db: &mut PgConnection,
+    parent_id: i32,
+    device_id: Uuid,
+    before: Option<Cursor>,
+    limit: i64,
+) -> Result<Vec<StoredMessage>, sqlx::Error> {
+    let ids = sqlx::query_scalar!(
+        r#"
+        SELECT m.id
+        FROM messages m
+        WHERE m.reply_to = $1
+            AND (m.created_at, m.id) < (coalesce($2, 'infinity'::timestamp), $3)
+            AND (m.expires_at IS NULL OR m.expires_at > now())
+        ORDER BY m.created_at DESC, m.id DESC
+        LIMIT $4;
+        "#,
+        parent_id,
+        before.map(|c| c.timestamp),
+        before.map_or(i32::MAX, |c| c.id),
+        limit
+    )
+    .fetch_all(&mut *db)
+    .await?;
+
+    get_messages(db, &ids, Some(device_id)).await
+}
@@ -9,6 +9,53 @@ pub struct Config {
     pub refresh_token_secret: String,
     pub refresh_token_ttl_sec: u64,
     pub access_token_ttl_sec: u64,
+    pub prekey_low_threshold: i64,
+
+    /// How often one user may fetch the prekey bundle of another.
+    pub prekey_claim_interval_sec: u64,
+    pub attachments_dir: String,
+    pub attachment_size_limit: u64,
+    pub purge_interval_sec: u64,
+
+    /// How long an uploaded attachment is kept while no message sends it.
+    pub attachment_unreferenced_ttl_sec: u64,
+
+    /// How long a requested account deletion can still be cancelled.
+    pub account_deletion_grace_sec: u64,
+
+    /// Whether a deleted account's messages stay with its friends, under a
+    /// pseudonym, instead of being deleted with it.
+    pub pseudonymize_deleted_messages: bool,
+
+    /// Keys access tokens are signed and verified with. Without any, they
+    /// are signed with `access_token_secret` instead.
+    #[serde(default)]
+    pub access_token_keys: Vec<AccessTokenKey>,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub enum TokenAlgorithm {
+    EdDSA,
+    ES256,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct AccessTokenKey {
+    /// Sent as the `kid` header of every token signed with the key.
+    pub kid: String,
+    pub algorithm: TokenAlgorithm,
+
+    /// PKCS#8 PEM of the private key. Retired keys may give the public key
+    /// alone, as SPKI PEM.
+    pub pem: String,
+
+    /// Retired keys no longer sign but still verify, so tokens issued
+    /// before a rotation stay valid until they expire. Exactly one key must
+    /// not be retired.
+    #[serde(default)]
+    pub retired: bool,
 }
 
 impl Default for Config {
@@ -19,6 +66,15 @@ impl Default for Config {
             refresh_token_secret: compute_random_32_bytes_key(),
             refresh_token_ttl_sec: 172800,
             access_token_ttl_sec: 3600,
+            prekey_low_threshold: 10,
+            prekey_claim_interval_sec: 60,
+            attachments_dir: "attachments".to_string(),
+            attachment_size_limit: 64 * 1024 * 1024,
+            purge_interval_sec: 60,
+            attachment_unreferenced_ttl_sec: 24 * 3600,
+            account_deletion_grace_sec: 7 * 24 * 3600,
+            pseudonymize_deleted_messages: false,
+            access_token_keys: Vec::new(),
         }
     }
 }
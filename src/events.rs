@@ -0,0 +1,147 @@
+use crate::{
+    chat::{MessageReactions, Receipt, StoredMessage},
+    groups::GroupMessage,
+};
+use rocket::{
+    serde::{Deserialize, Serialize},
+    tokio::sync::broadcast::{self, Receiver, Sender},
+};
+use sqlx::types::Uuid;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+pub mod handlers;
+pub mod listener;
+pub mod repo;
+
+/// Postgres channel every instance listens on.
+pub const CHANNEL: &str = "nanochat_events";
+
+const CHANNEL_CAPACITY: usize = 64;
+
+type DeviceChannels = HashMap<Uuid, Sender<Event>>;
+
+#[derive(Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(tag = "type", content = "payload", rename_all = "camelCase")]
+pub enum Event {
+    Message(StoredMessage),
+    MessageEdited(StoredMessage),
+    MessageDeleted(StoredMessage),
+    Receipt(Receipt),
+    Reactions(MessageReactions),
+    #[serde(rename_all = "camelCase")]
+    ChatRemoved {
+        chat_id: i32,
+    },
+    GroupMessage(GroupMessage),
+    PrekeysLow {
+        remaining: i64,
+    },
+}
+
+/// What goes over `NOTIFY`. Payloads are capped at 8000 bytes, so only
+/// identifiers travel and the listener loads the rows itself.
+#[derive(Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Notification {
+    Message {
+        id: i32,
+    },
+    MessageEdited {
+        id: i32,
+    },
+    MessageDeleted {
+        id: i32,
+    },
+    Receipt {
+        chat_id: i32,
+        user_id: Uuid,
+    },
+    Reactions {
+        message_id: i32,
+    },
+    /// The chat row is gone by the time this is heard, so both parties
+    /// travel along.
+    ChatRemoved {
+        chat_id: i32,
+        user_ids: [Uuid; 2],
+    },
+    GroupMessage {
+        id: i32,
+    },
+    PrekeysLow {
+        user_id: Uuid,
+        remaining: i64,
+    },
+}
+
+/// Keeps one broadcast channel per connected device, shared by all of
+/// that device's open sockets.
+#[derive(Clone, Default)]
+pub struct Hub {
+    channels: Arc<RwLock<HashMap<Uuid, DeviceChannels>>>,
+}
+
+impl Hub {
+    pub fn subscribe(&self, user_id: Uuid, device_id: Uuid) -> Receiver<Event> {
+        self.channels
+            .write()
+            .unwrap()
+            .entry(user_id)
+            .or_default()
+            .entry(device_id)
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+
+    /// Devices of `user_id` with at least one socket on this instance.
+    pub fn devices(&self, user_id: Uuid) -> Vec<Uuid> {
+        self.channels
+            .read()
+            .unwrap()
+            .get(&user_id)
+            .map(|d| d.keys().copied().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn publish(&self, user_id: Uuid, event: Event) {
+        if let Some(devices) = self.channels.read().unwrap().get(&user_id) {
+            for tx in devices.values() {
+                let _ = tx.send(event.clone());
+            }
+        }
+    }
+
+    pub fn publish_to_device(&self, user_id: Uuid, device_id: Uuid, event: Event) {
+        if let Some(tx) = self
+            .channels
+            .read()
+            .unwrap()
+            .get(&user_id)
+            .and_then(|d| d.get(&device_id))
+        {
+            let _ = tx.send(event);
+        }
+    }
+
+    pub fn prune(&self, user_id: Uuid, device_id: Uuid) {
+        let mut channels = self.channels.write().unwrap();
+
+        if let Some(devices) = channels.get_mut(&user_id) {
+            if devices
+                .get(&device_id)
+                .is_some_and(|tx| tx.receiver_count() == 0)
+            {
+                devices.remove(&device_id);
+            }
+
+            if devices.is_empty() {
+                channels.remove(&user_id);
+            }
+        }
+    }
+}
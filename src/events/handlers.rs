@@ -0,0 +1,41 @@
+use super::Hub;
+use crate::auth::AuthenticatedDevice;
+use rocket::{
+    futures::{SinkExt, StreamExt},
+    serde::json,
+    tokio::{select, sync::broadcast::error::RecvError},
+    State,
+};
+use rocket_ws::{Channel, Message, WebSocket};
+
+#[rocket::get("/")]
+pub fn subscribe(ws: WebSocket, device: AuthenticatedDevice, hub: &State<Hub>) -> Channel<'static> {
+    let hub = hub.inner().clone();
+    let mut events = hub.subscribe(device.user.id, device.id);
+
+    ws.channel(move |mut stream| {
+        Box::pin(async move {
+            loop {
+                select! {
+                    event = events.recv() => match event {
+                        Ok(e) => {
+                            let text = json::to_string(&e).expect("events are serializable");
+                            stream.send(Message::Text(text)).await?;
+                        }
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => break,
+                    },
+                    message = stream.next() => match message {
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                        Some(Ok(_)) => continue,
+                    },
+                }
+            }
+
+            drop(events);
+            hub.prune(device.user.id, device.id);
+
+            Ok(())
+        })
+    })
+}
@@ -0,0 +1,130 @@
+use super::{Event, Hub, Notification, CHANNEL};
+use crate::{
+    chat::{self, StoredMessage},
+    db::Db,
+    groups,
+};
+use rocket::{fairing::AdHoc, serde::json};
+use rocket_db_pools::Database;
+use sqlx::{postgres::PgListener, PgConnection, PgPool};
+
+/// Spawns a task that turns notifications from any instance into pushes
+/// to the sockets connected to this one.
+pub fn fairing() -> AdHoc {
+    AdHoc::on_liftoff("Event Listener", |rocket| {
+        Box::pin(async move {
+            let pool = PgPool::clone(Db::fetch(rocket).expect("database is attached"));
+            let hub = rocket.state::<Hub>().expect("hub is managed").clone();
+
+            rocket::tokio::spawn(listen(pool, hub));
+        })
+    })
+}
+
+async fn listen(pool: PgPool, hub: Hub) {
+    let mut listener = match PgListener::connect_with(&pool).await {
+        Ok(l) => l,
+        Err(e) => return rocket::error!("failed to connect event listener: {}", e),
+    };
+
+    if let Err(e) = listener.listen(CHANNEL).await {
+        return rocket::error!("failed to listen on {}: {}", CHANNEL, e);
+    }
+
+    loop {
+        // On connection loss the listener reconnects on the next call, but
+        // whatever was notified in between is gone.
+        let notification = match listener.recv().await {
+            Ok(n) => n,
+            Err(sqlx::Error::PoolClosed) => break,
+            Err(e) => {
+                rocket::warn!("event listener connection lost: {}", e);
+                continue;
+            }
+        };
+
+        match json::from_str(notification.payload()) {
+            Ok(n) => {
+                if let Err(e) = dispatch(&pool, &hub, n).await {
+                    rocket::error!("failed to dispatch notification: {}", e);
+                }
+            }
+            Err(e) => rocket::warn!("ignoring malformed notification: {}", e),
+        }
+    }
+}
+
+async fn dispatch(pool: &PgPool, hub: &Hub, notification: Notification) -> Result<(), sqlx::Error> {
+    let mut db = pool.acquire().await?;
+
+    match notification {
+        Notification::Message { id } => publish_message(&mut db, hub, id, Event::Message).await?,
+        Notification::MessageEdited { id } => {
+            publish_message(&mut db, hub, id, Event::MessageEdited).await?
+        }
+        Notification::MessageDeleted { id } => {
+            publish_message(&mut db, hub, id, Event::MessageDeleted).await?
+        }
+        Notification::Receipt { chat_id, user_id } => {
+            if let Some(receipt) = chat::repo::get_receipt(&mut db, chat_id, user_id).await? {
+                for participant_id in chat::repo::get_participant_ids(&mut db, chat_id).await? {
+                    hub.publish(participant_id, Event::Receipt(receipt.clone()));
+                }
+            }
+        }
+        Notification::Reactions { message_id } => {
+            if let Some(reactions) = chat::repo::get_message_reactions(&mut db, message_id).await? {
+                for participant_id in
+                    chat::repo::get_participant_ids(&mut db, reactions.chat_id).await?
+                {
+                    hub.publish(participant_id, Event::Reactions(reactions.clone()));
+                }
+            }
+        }
+        Notification::ChatRemoved { chat_id, user_ids } => {
+            for user_id in user_ids {
+                hub.publish(user_id, Event::ChatRemoved { chat_id });
+            }
+        }
+        Notification::GroupMessage { id } => {
+            if let Some(message) = groups::repo::get_message(&mut db, id).await? {
+                for member_id in groups::repo::get_member_ids(&mut db, message.group_id).await? {
+                    hub.publish(member_id, Event::GroupMessage(message.clone()));
+                }
+            }
+        }
+        Notification::PrekeysLow { user_id, remaining } => {
+            hub.publish(user_id, Event::PrekeysLow { remaining });
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends each connected device of both parties the message as that device
+/// should see it, with its own envelope as the content.
+async fn publish_message(
+    db: &mut PgConnection,
+    hub: &Hub,
+    id: i32,
+    event: fn(StoredMessage) -> Event,
+) -> Result<(), sqlx::Error> {
+    let Some(message) = chat::repo::get_message(db, id).await? else {
+        return Ok(());
+    };
+    let envelopes = chat::repo::get_envelopes(db, id).await?;
+
+    for user_id in [message.recipient_id, message.sender_id] {
+        for device_id in hub.devices(user_id) {
+            let mut message = message.clone();
+
+            if let Some(e) = envelopes.iter().find(|e| e.device_id == device_id) {
+                message.content = e.content.clone();
+            }
+
+            hub.publish_to_device(user_id, device_id, event(message));
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,13 @@
+use super::{Notification, CHANNEL};
+use rocket::serde::json;
+use sqlx::PgConnection;
+
+pub async fn notify(db: &mut PgConnection, notification: &Notification) -> Result<(), sqlx::Error> {
+    let payload = json::to_string(notification).expect("notifications are serializable");
+
+    sqlx::query!("SELECT pg_notify($1, $2);", CHANNEL, payload)
+        .execute(&mut *db)
+        .await?;
+
+    Ok(())
+}
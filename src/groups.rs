@@ -0,0 +1,115 @@
+use crate::{chat::Cursor, users::option_hex, Validate};
+use rocket::serde::{uuid::Uuid, Deserialize, Serialize};
+
+pub mod handlers;
+pub mod repo;
+
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq, sqlx::Type)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+#[sqlx(type_name = "group_role", rename_all = "lowercase")]
+pub enum Role {
+    Owner,
+    Admin,
+    Member,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct Group {
+    id: i32,
+    name: String,
+    created_at: sqlx::types::chrono::NaiveDateTime,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct Member {
+    group_id: i32,
+    user_id: Uuid,
+    role: Role,
+    invited_by: Option<Uuid>,
+
+    #[serde(with = "option_hex")]
+    public_key: Option<Vec<u8>>,
+
+    #[serde(with = "option_hex")]
+    key_signature: Option<Vec<u8>>,
+    created_at: sqlx::types::chrono::NaiveDateTime,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct NewGroup {
+    name: String,
+
+    #[serde(with = "hex::serde")]
+    public_key: Vec<u8>,
+
+    #[serde(with = "hex::serde")]
+    signature: Vec<u8>,
+}
+
+impl Validate for NewGroup {
+    fn validate(&self) -> bool {
+        !self.name.is_empty()
+            && self.name.chars().count() <= 64
+            && self.public_key.len() == 32
+            && self.signature.len() == 64
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RoleChange {
+    role: Role,
+}
+
+impl Validate for RoleChange {
+    fn validate(&self) -> bool {
+        self.role != Role::Owner
+    }
+}
+
+#[derive(Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct GroupMessage {
+    pub id: i32,
+    pub group_id: i32,
+    pub sender_id: Uuid,
+
+    #[serde(with = "hex::serde")]
+    pub content: Vec<u8>,
+    pub created_at: sqlx::types::chrono::NaiveDateTime,
+}
+
+impl From<&GroupMessage> for Cursor {
+    fn from(message: &GroupMessage) -> Self {
+        Cursor {
+            timestamp: message.created_at,
+            id: message.id,
+        }
+    }
+}
+
+/// A page of group history, newest first. `next_cursor` leads to older
+/// messages.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct GroupMessageList {
+    pub messages: Vec<GroupMessage>,
+    pub next_cursor: Option<Cursor>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct CreatedGroupMessage {
+    #[serde(with = "hex::serde")]
+    pub content: Vec<u8>,
+}
@@ -0,0 +1,228 @@
+use super::{
+    repo, CreatedGroupMessage, Group, GroupMessage, GroupMessageList, Member, NewGroup, RoleChange,
+};
+use crate::{
+    auth::AuthenticatedUser,
+    chat::Cursor,
+    db::Db,
+    keys::{self, KeyUploadError},
+    users::PublicKey,
+    Validate,
+};
+use rocket::{http::Status, serde::json::Json};
+use rocket_db_pools::Connection;
+use sqlx::types::Uuid;
+
+const DEFAULT_MESSAGE_PAGE: i64 = 50;
+const MAX_MESSAGE_PAGE: i64 = 100;
+
+#[rocket::post("/", data = "<body>")]
+pub async fn create_group(
+    mut db: Connection<Db>,
+    body: Json<NewGroup>,
+    owner: AuthenticatedUser,
+) -> Result<Json<Group>, KeyUploadError> {
+    if !body.validate() {
+        return Err(Status::UnprocessableEntity.into());
+    }
+
+    keys::verify_signed_key(&mut db, owner.id, &body.public_key, &body.signature).await?;
+
+    let group = repo::create_group(
+        &mut db,
+        owner.id,
+        &body.name,
+        &body.public_key,
+        &body.signature,
+    )
+    .await
+    .or(Err(Status::InternalServerError))?;
+
+    Ok(Json(group))
+}
+
+#[rocket::get("/")]
+pub async fn get_groups(
+    mut db: Connection<Db>,
+    user: AuthenticatedUser,
+) -> Result<Json<Vec<Group>>, Status> {
+    let groups = repo::get_groups(&mut db, user.id, true)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    Ok(Json(groups))
+}
+
+#[rocket::get("/invites")]
+pub async fn get_invites(
+    mut db: Connection<Db>,
+    user: AuthenticatedUser,
+) -> Result<Json<Vec<Group>>, Status> {
+    let groups = repo::get_groups(&mut db, user.id, false)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    Ok(Json(groups))
+}
+
+#[rocket::get("/<group_id>/members")]
+pub async fn get_members(
+    mut db: Connection<Db>,
+    user: AuthenticatedUser,
+    group_id: i32,
+) -> Result<Json<Vec<Member>>, Status> {
+    let is_member = repo::is_member(&mut db, group_id, user.id)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    if !is_member {
+        return Err(Status::Forbidden);
+    }
+
+    let members = repo::get_members(&mut db, group_id)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    Ok(Json(members))
+}
+
+#[rocket::post("/<group_id>/members/<user_id>/invite")]
+pub async fn invite(
+    mut db: Connection<Db>,
+    group_id: i32,
+    user_id: Uuid,
+    inviter: AuthenticatedUser,
+) -> Result<Json<Member>, Status> {
+    let member = repo::invite_member(&mut db, group_id, inviter.id, user_id)
+        .await
+        .map_err(|e| match e.as_database_error() {
+            Some(e) if e.is_unique_violation() => Status::Conflict,
+            Some(e) if e.is_foreign_key_violation() => Status::NotFound,
+            _ => Status::InternalServerError,
+        })?
+        .ok_or(Status::Forbidden)?;
+
+    Ok(Json(member))
+}
+
+#[rocket::post("/<group_id>/accept", data = "<body>")]
+pub async fn accept(
+    mut db: Connection<Db>,
+    body: Json<PublicKey>,
+    group_id: i32,
+    user: AuthenticatedUser,
+) -> Result<Json<Member>, KeyUploadError> {
+    if !body.validate() {
+        return Err(Status::UnprocessableEntity.into());
+    }
+
+    keys::verify_signed_key(&mut db, user.id, &body.public_key, &body.signature).await?;
+
+    let member = repo::accept_invite(
+        &mut db,
+        group_id,
+        user.id,
+        &body.public_key,
+        &body.signature,
+    )
+    .await
+    .map_err(|e| match e.as_database_error() {
+        Some(e) if e.is_check_violation() => Status::UnprocessableEntity,
+        _ => Status::InternalServerError,
+    })?
+    .ok_or(Status::NotFound)?;
+
+    Ok(Json(member))
+}
+
+#[rocket::put("/<group_id>/members/<user_id>/role", data = "<body>")]
+pub async fn change_role(
+    mut db: Connection<Db>,
+    body: Json<RoleChange>,
+    group_id: i32,
+    user_id: Uuid,
+    owner: AuthenticatedUser,
+) -> Result<Json<Member>, Status> {
+    if !body.validate() {
+        return Err(Status::UnprocessableEntity);
+    }
+
+    let member = repo::change_role(&mut db, group_id, owner.id, user_id, body.role)
+        .await
+        .or(Err(Status::InternalServerError))?
+        .ok_or(Status::NotFound)?;
+
+    Ok(Json(member))
+}
+
+#[rocket::delete("/<group_id>/members/<user_id>")]
+pub async fn remove_member(
+    mut db: Connection<Db>,
+    group_id: i32,
+    user_id: Uuid,
+    actor: AuthenticatedUser,
+) -> Result<(), Status> {
+    let result = repo::remove_member(&mut db, group_id, actor.id, user_id)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    if result.rows_affected() == 0 {
+        return Err(Status::NotFound);
+    }
+
+    Ok(())
+}
+
+#[rocket::post("/<group_id>/messages", data = "<body>")]
+pub async fn insert_message(
+    mut db: Connection<Db>,
+    body: Json<CreatedGroupMessage>,
+    group_id: i32,
+    sender: AuthenticatedUser,
+) -> Result<Json<GroupMessage>, Status> {
+    let message = repo::insert_message(&mut db, group_id, sender.id, &body.content)
+        .await
+        .or(Err(Status::InternalServerError))?
+        .ok_or(Status::Forbidden)?;
+
+    Ok(Json(message))
+}
+
+#[rocket::get("/<group_id>/messages?<before>&<limit>")]
+pub async fn get_message_page(
+    mut db: Connection<Db>,
+    user: AuthenticatedUser,
+    group_id: i32,
+    before: Option<String>,
+    limit: Option<i64>,
+) -> Result<Json<GroupMessageList>, Status> {
+    let limit = limit
+        .unwrap_or(DEFAULT_MESSAGE_PAGE)
+        .clamp(1, MAX_MESSAGE_PAGE);
+    let before = before
+        .map(|c| c.parse::<Cursor>())
+        .transpose()
+        .or(Err(Status::UnprocessableEntity))?;
+
+    let is_member = repo::is_member(&mut db, group_id, user.id)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    if !is_member {
+        return Err(Status::Forbidden);
+    }
+
+    // One extra row tells whether there is more to page through.
+    let mut messages = repo::get_message_page(&mut db, group_id, before, limit + 1)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    let more = messages.len() as i64 > limit;
+    messages.truncate(limit as usize);
+    let next_cursor = messages.last().filter(|_| more).map(Cursor::from);
+
+    Ok(Json(GroupMessageList {
+        messages,
+        next_cursor,
+    }))
+}
@@ -0,0 +1,295 @@
+use super::{Group, GroupMessage, Member, Role};
+use crate::{
+    chat::Cursor,
+    events::{self, Notification},
+};
+use sqlx::{postgres::PgQueryResult, types::Uuid, Connection, PgConnection};
+
+pub async fn create_group(
+    db: &mut PgConnection,
+    owner_id: Uuid,
+    name: &str,
+    owner_public_key: &[u8],
+    owner_key_signature: &[u8],
+) -> Result<Group, sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    let group = sqlx::query_as!(
+        Group,
+        r"INSERT INTO groups (name) VALUES ($1) RETURNING *;",
+        name
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO group_members (group_id, user_id, role, public_key, key_signature)
+        VALUES ($1, $2, 'owner', $3, $4);
+        "#,
+        group.id,
+        owner_id,
+        owner_public_key,
+        owner_key_signature
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(group)
+}
+
+pub async fn get_groups(
+    db: &mut PgConnection,
+    user_id: Uuid,
+    accepted: bool,
+) -> Result<Vec<Group>, sqlx::Error> {
+    sqlx::query_as!(
+        Group,
+        r#"
+        SELECT g.id, g.name, g.created_at
+        FROM groups g
+        JOIN group_members m ON m.group_id = g.id
+        WHERE m.user_id = $1 AND (m.public_key IS NOT NULL) = $2
+        ORDER BY g.created_at DESC;
+        "#,
+        user_id,
+        accepted
+    )
+    .fetch_all(&mut *db)
+    .await
+}
+
+pub async fn get_members(db: &mut PgConnection, group_id: i32) -> Result<Vec<Member>, sqlx::Error> {
+    sqlx::query_as!(
+        Member,
+        r#"
+        SELECT group_id, user_id, role AS "role: Role", invited_by, public_key, key_signature, created_at
+        FROM group_members
+        WHERE group_id = $1
+        ORDER BY created_at;
+        "#,
+        group_id
+    )
+    .fetch_all(&mut *db)
+    .await
+}
+
+pub async fn get_member_ids(
+    db: &mut PgConnection,
+    group_id: i32,
+) -> Result<Vec<Uuid>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT user_id FROM group_members
+        WHERE group_id = $1 AND public_key IS NOT NULL;
+        "#,
+        group_id
+    )
+    .fetch_all(&mut *db)
+    .await
+}
+
+pub async fn is_member(
+    db: &mut PgConnection,
+    group_id: i32,
+    user_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS(
+            SELECT 1 FROM group_members
+            WHERE group_id = $1 AND user_id = $2 AND public_key IS NOT NULL
+        ) AS "exists!";
+        "#,
+        group_id,
+        user_id
+    )
+    .fetch_one(&mut *db)
+    .await
+}
+
+pub async fn invite_member(
+    db: &mut PgConnection,
+    group_id: i32,
+    inviter_id: Uuid,
+    user_id: Uuid,
+) -> Result<Option<Member>, sqlx::Error> {
+    sqlx::query_as!(
+        Member,
+        r#"
+        INSERT INTO group_members (group_id, user_id, invited_by)
+        SELECT $1, $3, $2
+        WHERE EXISTS(
+            SELECT 1 FROM group_members
+            WHERE group_id = $1 AND user_id = $2 AND public_key IS NOT NULL
+                AND role IN ('owner', 'admin')
+        ) AND NOT is_blocked($2, $3)
+        RETURNING group_id, user_id, role AS "role: Role", invited_by, public_key, key_signature, created_at;
+        "#,
+        group_id,
+        inviter_id,
+        user_id
+    )
+    .fetch_optional(&mut *db)
+    .await
+}
+
+pub async fn accept_invite(
+    db: &mut PgConnection,
+    group_id: i32,
+    user_id: Uuid,
+    public_key: &[u8],
+    key_signature: &[u8],
+) -> Result<Option<Member>, sqlx::Error> {
+    sqlx::query_as!(
+        Member,
+        r#"
+        UPDATE group_members
+        SET
+            public_key = $3,
+            key_signature = $4
+        WHERE group_id = $1 AND user_id = $2 AND public_key IS NULL
+            AND NOT is_blocked($2, invited_by)
+        RETURNING group_id, user_id, role AS "role: Role", invited_by, public_key, key_signature, created_at;
+        "#,
+        group_id,
+        user_id,
+        public_key,
+        key_signature
+    )
+    .fetch_optional(&mut *db)
+    .await
+}
+
+pub async fn change_role(
+    db: &mut PgConnection,
+    group_id: i32,
+    owner_id: Uuid,
+    user_id: Uuid,
+    role: Role,
+) -> Result<Option<Member>, sqlx::Error> {
+    sqlx::query_as!(
+        Member,
+        r#"
+        UPDATE group_members
+        SET
+            role = $4
+        WHERE group_id = $1 AND user_id = $3 AND role <> 'owner' AND public_key IS NOT NULL
+            AND EXISTS(
+                SELECT 1 FROM group_members
+                WHERE group_id = $1 AND user_id = $2 AND role = 'owner'
+            )
+        RETURNING group_id, user_id, role AS "role: Role", invited_by, public_key, key_signature, created_at;
+        "#,
+        group_id,
+        owner_id,
+        user_id,
+        role as Role
+    )
+    .fetch_optional(&mut *db)
+    .await
+}
+
+/// Members may always leave or decline. Owners may remove anyone else and
+/// admins may remove plain members. The owner itself can't be removed.
+pub async fn remove_member(
+    db: &mut PgConnection,
+    group_id: i32,
+    actor_id: Uuid,
+    user_id: Uuid,
+) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!(
+        r#"
+        DELETE FROM group_members m
+        WHERE m.group_id = $1 AND m.user_id = $3 AND m.role <> 'owner' AND (
+            m.user_id = $2 OR EXISTS(
+                SELECT 1 FROM group_members a
+                WHERE a.group_id = $1 AND a.user_id = $2 AND a.public_key IS NOT NULL
+                    AND (a.role = 'owner' OR (a.role = 'admin' AND m.role = 'member'))
+            )
+        );
+        "#,
+        group_id,
+        actor_id,
+        user_id
+    )
+    .execute(&mut *db)
+    .await
+}
+
+pub async fn get_message(
+    db: &mut PgConnection,
+    id: i32,
+) -> Result<Option<GroupMessage>, sqlx::Error> {
+    sqlx::query_as!(
+        GroupMessage,
+        r"SELECT * FROM group_messages WHERE id = $1;",
+        id
+    )
+    .fetch_optional(&mut *db)
+    .await
+}
+
+/// Messages older than `before`, or the latest ones without it.
+pub async fn get_message_page(
+    db: &mut PgConnection,
+    group_id: i32,
+    before: Option<Cursor>,
+    limit: i64,
+) -> Result<Vec<GroupMessage>, sqlx::Error> {
+    sqlx::query_as!(
+        GroupMessage,
+        r#"
+        SELECT * FROM group_messages
+        WHERE group_id = $1
+            AND (created_at, id) < (coalesce($2, 'infinity'::timestamp), $3)
+        ORDER BY created_at DESC, id DESC
+        LIMIT $4;
+        "#,
+        group_id,
+        before.map(|c| c.timestamp),
+        before.map_or(i32::MAX, |c| c.id),
+        limit
+    )
+    .fetch_all(&mut *db)
+    .await
+}
+
+pub async fn insert_message(
+    db: &mut PgConnection,
+    group_id: i32,
+    sender_id: Uuid,
+    content: &[u8],
+) -> Result<Option<GroupMessage>, sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    let message = sqlx::query_as!(
+        GroupMessage,
+        r#"
+        INSERT INTO group_messages
+            (group_id, sender_id, content)
+        SELECT
+            $1, $2, $3
+        WHERE EXISTS(
+            SELECT 1 FROM group_members
+            WHERE group_id = $1 AND user_id = $2 AND public_key IS NOT NULL
+        )
+        RETURNING *;
+        "#,
+        group_id,
+        sender_id,
+        content,
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    if let Some(ref m) = message {
+        events::repo::notify(&mut tx, &Notification::GroupMessage { id: m.id }).await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(message)
+}
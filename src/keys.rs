@@ -0,0 +1,175 @@
+use crate::Validate;
+use ed25519_dalek::{Signature, VerifyingKey};
+use rocket::{
+    http::Status,
+    serde::{uuid::Uuid, Deserialize, Serialize},
+    Responder,
+};
+use sqlx::PgConnection;
+
+pub mod handlers;
+pub mod repo;
+
+const MAX_PREKEY_BATCH: usize = 100;
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct IdentityKey {
+    user_id: Uuid,
+
+    #[serde(with = "hex::serde")]
+    public_key: Vec<u8>,
+    created_at: sqlx::types::chrono::NaiveDateTime,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct NewIdentityKey {
+    #[serde(with = "hex::serde")]
+    public_key: Vec<u8>,
+}
+
+impl Validate for NewIdentityKey {
+    fn validate(&self) -> bool {
+        <[u8; 32]>::try_from(self.public_key.as_slice())
+            .is_ok_and(|k| VerifyingKey::from_bytes(&k).is_ok_and(|k| !k.is_weak()))
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct SignedPrekey {
+    key_id: i32,
+
+    #[serde(with = "hex::serde")]
+    public_key: Vec<u8>,
+
+    #[serde(with = "hex::serde")]
+    signature: Vec<u8>,
+}
+
+impl Validate for SignedPrekey {
+    fn validate(&self) -> bool {
+        self.public_key.len() == 32 && self.signature.len() == 64
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct OneTimePrekey {
+    key_id: i32,
+
+    #[serde(with = "hex::serde")]
+    public_key: Vec<u8>,
+
+    #[serde(with = "hex::serde")]
+    signature: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct OneTimePrekeys {
+    prekeys: Vec<OneTimePrekey>,
+}
+
+impl Validate for OneTimePrekeys {
+    fn validate(&self) -> bool {
+        !self.prekeys.is_empty()
+            && self.prekeys.len() <= MAX_PREKEY_BATCH
+            && self
+                .prekeys
+                .iter()
+                .all(|p| p.public_key.len() == 32 && p.signature.len() == 64)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct PrekeyCount {
+    remaining: i64,
+    low: bool,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct PrekeyBundle {
+    user_id: Uuid,
+
+    #[serde(with = "hex::serde")]
+    identity_key: Vec<u8>,
+    signed_prekey: SignedPrekey,
+    one_time_prekey: Option<OneTimePrekey>,
+}
+
+/// Why a signed key upload was turned down. The first two variants carry a
+/// body so clients can tell them apart from a malformed request.
+#[derive(Responder)]
+pub enum KeyUploadError {
+    #[response(status = 409)]
+    MissingIdentityKey(&'static str),
+
+    #[response(status = 422)]
+    InvalidSignature(&'static str),
+    Status(Status),
+}
+
+impl From<Status> for KeyUploadError {
+    fn from(status: Status) -> Self {
+        KeyUploadError::Status(status)
+    }
+}
+
+/// Checks that `signature` is the Ed25519 signature of `public_key` under
+/// the identity key registered by `user_id`.
+pub async fn verify_signed_key(
+    db: &mut PgConnection,
+    user_id: Uuid,
+    public_key: &[u8],
+    signature: &[u8],
+) -> Result<(), KeyUploadError> {
+    verify_signed_keys(db, user_id, [(public_key, signature)]).await
+}
+
+/// Checks that each `(public_key, signature)` pair in `keys` is an Ed25519
+/// signature under the identity key registered by `user_id`, which is read
+/// once for the whole batch.
+pub async fn verify_signed_keys<'a>(
+    db: &mut PgConnection,
+    user_id: Uuid,
+    keys: impl IntoIterator<Item = (&'a [u8], &'a [u8])>,
+) -> Result<(), KeyUploadError> {
+    let identity_key = repo::get_identity_key(db, user_id)
+        .await
+        .or(Err(Status::InternalServerError))?
+        .ok_or(KeyUploadError::MissingIdentityKey(
+            "register an identity key before uploading signed keys",
+        ))?;
+
+    for (public_key, signature) in keys {
+        if !verify(&identity_key, public_key, signature) {
+            return Err(KeyUploadError::InvalidSignature(
+                "signature does not match the registered identity key",
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn verify(identity_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    let Ok(identity_key) = <[u8; 32]>::try_from(identity_key) else {
+        return false;
+    };
+    let Ok(signature) = Signature::from_slice(signature) else {
+        return false;
+    };
+
+    VerifyingKey::from_bytes(&identity_key)
+        .is_ok_and(|k| k.verify_strict(message, &signature).is_ok())
+}
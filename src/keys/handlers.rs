@@ -0,0 +1,134 @@
+use super::{
+    repo, verify_signed_key, verify_signed_keys, IdentityKey, KeyUploadError, NewIdentityKey,
+    OneTimePrekeys, PrekeyBundle, PrekeyCount, SignedPrekey,
+};
+use crate::{auth::AuthenticatedUser, config::Config, db::Db, Validate};
+use rocket::{http::Status, serde::json::Json, State};
+use rocket_db_pools::Connection;
+use sqlx::types::Uuid;
+
+#[rocket::put("/identity", data = "<body>")]
+pub async fn put_identity_key(
+    mut db: Connection<Db>,
+    body: Json<NewIdentityKey>,
+    user: AuthenticatedUser,
+) -> Result<Json<IdentityKey>, Status> {
+    if !body.validate() {
+        return Err(Status::UnprocessableEntity);
+    }
+
+    let identity_key = repo::upsert_identity_key(&mut db, user.id, &body.public_key)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    Ok(Json(identity_key))
+}
+
+#[rocket::put("/signed-prekey", data = "<body>")]
+pub async fn put_signed_prekey(
+    mut db: Connection<Db>,
+    body: Json<SignedPrekey>,
+    user: AuthenticatedUser,
+) -> Result<Json<SignedPrekey>, KeyUploadError> {
+    if !body.validate() {
+        return Err(Status::UnprocessableEntity.into());
+    }
+
+    verify_signed_key(&mut db, user.id, &body.public_key, &body.signature).await?;
+
+    let prekey = repo::upsert_signed_prekey(&mut db, user.id, &body)
+        .await
+        .map_err(|e| match e.as_database_error() {
+            Some(e) if e.is_foreign_key_violation() => Status::Conflict,
+            _ => Status::InternalServerError,
+        })?;
+
+    Ok(Json(prekey))
+}
+
+#[rocket::post("/one-time-prekeys", data = "<body>")]
+pub async fn post_one_time_prekeys(
+    mut db: Connection<Db>,
+    body: Json<OneTimePrekeys>,
+    user: AuthenticatedUser,
+    config: &State<Config>,
+) -> Result<Json<PrekeyCount>, KeyUploadError> {
+    if !body.validate() {
+        return Err(Status::UnprocessableEntity.into());
+    }
+
+    let signed_keys = body
+        .prekeys
+        .iter()
+        .map(|p| (p.public_key.as_slice(), p.signature.as_slice()));
+
+    verify_signed_keys(&mut db, user.id, signed_keys).await?;
+
+    let remaining = repo::insert_one_time_prekeys(&mut db, user.id, &body.prekeys)
+        .await
+        .map_err(|e| match e.as_database_error() {
+            Some(e) if e.is_unique_violation() => Status::Conflict,
+            Some(e) if e.is_foreign_key_violation() => Status::Conflict,
+            _ => Status::InternalServerError,
+        })?;
+
+    Ok(Json(PrekeyCount {
+        remaining,
+        low: remaining < config.prekey_low_threshold,
+    }))
+}
+
+#[rocket::get("/one-time-prekeys/count")]
+pub async fn get_prekey_count(
+    mut db: Connection<Db>,
+    user: AuthenticatedUser,
+    config: &State<Config>,
+) -> Result<Json<PrekeyCount>, Status> {
+    let remaining = repo::count_one_time_prekeys(&mut db, user.id)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    Ok(Json(PrekeyCount {
+        remaining,
+        low: remaining < config.prekey_low_threshold,
+    }))
+}
+
+/// Hands out the bundle of a user the caller shares a chat or invite with,
+/// at most once per `prekey_claim_interval_sec` for the same pair. Once the
+/// one-time prekeys run out the bundle comes without one.
+#[rocket::post("/<user_id>/bundle")]
+pub async fn consume_bundle(
+    mut db: Connection<Db>,
+    user_id: Uuid,
+    claimer: AuthenticatedUser,
+    config: &State<Config>,
+) -> Result<Json<PrekeyBundle>, Status> {
+    let allowed = repo::may_claim_prekeys(&mut db, claimer.id, user_id)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    if !allowed {
+        return Err(Status::NotFound);
+    }
+
+    let claimed = repo::record_prekey_claim(
+        &mut db,
+        claimer.id,
+        user_id,
+        config.prekey_claim_interval_sec,
+    )
+    .await
+    .or(Err(Status::InternalServerError))?;
+
+    if !claimed {
+        return Err(Status::TooManyRequests);
+    }
+
+    let bundle = repo::consume_prekey_bundle(&mut db, user_id, config.prekey_low_threshold)
+        .await
+        .or(Err(Status::InternalServerError))?
+        .ok_or(Status::NotFound)?;
+
+    Ok(Json(bundle))
+}
@@ -0,0 +1,241 @@
+use super::{IdentityKey, OneTimePrekey, PrekeyBundle, SignedPrekey};
+use crate::events::{self, Notification};
+use sqlx::{types::Uuid, Connection, PgConnection};
+
+pub async fn get_identity_key(
+    db: &mut PgConnection,
+    user_id: Uuid,
+) -> Result<Option<Vec<u8>>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r"SELECT public_key FROM identity_keys WHERE user_id = $1;",
+        user_id
+    )
+    .fetch_optional(&mut *db)
+    .await
+}
+
+/// Replacing the identity key drops every prekey signed under the old one.
+pub async fn upsert_identity_key(
+    db: &mut PgConnection,
+    user_id: Uuid,
+    public_key: &[u8],
+) -> Result<IdentityKey, sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    sqlx::query!(
+        r"DELETE FROM identity_keys WHERE user_id = $1 AND public_key <> $2;",
+        user_id,
+        public_key
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let identity_key = sqlx::query_as!(
+        IdentityKey,
+        r#"
+        INSERT INTO identity_keys (user_id, public_key)
+        VALUES ($1, $2)
+        ON CONFLICT (user_id) DO UPDATE
+        SET
+            public_key = EXCLUDED.public_key
+        RETURNING *;
+        "#,
+        user_id,
+        public_key
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(identity_key)
+}
+
+pub async fn upsert_signed_prekey(
+    db: &mut PgConnection,
+    user_id: Uuid,
+    prekey: &SignedPrekey,
+) -> Result<SignedPrekey, sqlx::Error> {
+    sqlx::query_as!(
+        SignedPrekey,
+        r#"
+        INSERT INTO signed_prekeys (user_id, key_id, public_key, signature)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (user_id) DO UPDATE
+        SET
+            key_id = EXCLUDED.key_id,
+            public_key = EXCLUDED.public_key,
+            signature = EXCLUDED.signature,
+            created_at = NOW()
+        RETURNING key_id, public_key, signature;
+        "#,
+        user_id,
+        prekey.key_id,
+        prekey.public_key,
+        prekey.signature
+    )
+    .fetch_one(&mut *db)
+    .await
+}
+
+pub async fn insert_one_time_prekeys(
+    db: &mut PgConnection,
+    user_id: Uuid,
+    prekeys: &[OneTimePrekey],
+) -> Result<i64, sqlx::Error> {
+    let key_ids = prekeys.iter().map(|p| p.key_id).collect::<Vec<_>>();
+    let public_keys = prekeys
+        .iter()
+        .map(|p| p.public_key.clone())
+        .collect::<Vec<_>>();
+    let signatures = prekeys
+        .iter()
+        .map(|p| p.signature.clone())
+        .collect::<Vec<_>>();
+
+    let mut tx = db.begin().await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO one_time_prekeys (user_id, key_id, public_key, signature)
+        SELECT $1, * FROM UNNEST($2::integer[], $3::bytea[], $4::bytea[]);
+        "#,
+        user_id,
+        &key_ids,
+        &public_keys,
+        &signatures
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let remaining = count_one_time_prekeys(&mut tx, user_id).await?;
+    tx.commit().await?;
+
+    Ok(remaining)
+}
+
+pub async fn count_one_time_prekeys(
+    db: &mut PgConnection,
+    user_id: Uuid,
+) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT count(*) AS "count!" FROM one_time_prekeys WHERE user_id = $1;"#,
+        user_id
+    )
+    .fetch_one(&mut *db)
+    .await
+}
+
+/// Whether `claimer_id` may fetch the bundle of `user_id`, which takes a
+/// chat or invite between the two and no block either way.
+pub async fn may_claim_prekeys(
+    db: &mut PgConnection,
+    claimer_id: Uuid,
+    user_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS(
+            SELECT 1 FROM chat_members WHERE user_id = $1 AND friend_id = $2
+        ) AND NOT is_blocked($1, $2) AS "allowed!";
+        "#,
+        claimer_id,
+        user_id
+    )
+    .fetch_one(&mut *db)
+    .await
+}
+
+/// Records that `claimer_id` fetched the bundle of `user_id`, unless they
+/// already did within the last `interval_sec`.
+pub async fn record_prekey_claim(
+    db: &mut PgConnection,
+    claimer_id: Uuid,
+    user_id: Uuid,
+    interval_sec: u64,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO prekey_claims (claimer_id, user_id)
+        VALUES ($1, $2)
+        ON CONFLICT (claimer_id, user_id) DO UPDATE
+        SET claimed_at = now()
+        WHERE prekey_claims.claimed_at <= now() - make_interval(secs => $3);
+        "#,
+        claimer_id,
+        user_id,
+        interval_sec as f64
+    )
+    .execute(&mut *db)
+    .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+/// Hands out the user's bundle, consuming the oldest one-time prekey.
+/// Concurrent callers never receive the same one-time prekey. When the
+/// supply drops below `low_threshold` the owner is notified.
+pub async fn consume_prekey_bundle(
+    db: &mut PgConnection,
+    user_id: Uuid,
+    low_threshold: i64,
+) -> Result<Option<PrekeyBundle>, sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    let keys = sqlx::query!(
+        r#"
+        SELECT
+            i.public_key AS identity_key,
+            s.key_id,
+            s.public_key,
+            s.signature
+        FROM identity_keys i
+        JOIN signed_prekeys s ON s.user_id = i.user_id
+        WHERE i.user_id = $1;
+        "#,
+        user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some(keys) = keys else {
+        return Ok(None);
+    };
+
+    let one_time_prekey = sqlx::query_as!(
+        OneTimePrekey,
+        r#"
+        DELETE FROM one_time_prekeys
+        WHERE id = (
+            SELECT id FROM one_time_prekeys
+            WHERE user_id = $1
+            ORDER BY id
+            LIMIT 1
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING key_id, public_key, signature;
+        "#,
+        user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let remaining = count_one_time_prekeys(&mut tx, user_id).await?;
+
+    if remaining < low_threshold {
+        events::repo::notify(&mut tx, &Notification::PrekeysLow { user_id, remaining }).await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(Some(PrekeyBundle {
+        user_id,
+        identity_key: keys.identity_key,
+        signed_prekey: SignedPrekey {
+            key_id: keys.key_id,
+            public_key: keys.public_key,
+            signature: keys.signature,
+        },
+        one_time_prekey,
+    }))
+}
@@ -1,10 +1,151 @@
+use crate::{
+    attachments::handlers::{download, upload},
+    auth::handlers::{
+        change_password, confirm_totp, disable_totp, enroll_totp, get_sessions, jwks, logout,
+        recover_account, refresh, regenerate_recovery_codes, revoke_other_sessions, revoke_session,
+        signin, signin_second_factor, signup,
+    },
+    chat::{
+        handlers::{
+            delete_message, delete_reaction, edit_message, get_chats, get_replies, insert_message,
+            put_reaction,
+        },
+        purge,
+    },
+    config::Config,
+    db::Db,
+    events::{handlers::subscribe, listener, Hub},
+    groups::handlers::{
+        accept as accept_group, change_role, create_group, get_groups, get_invites, get_members,
+        get_message_page as get_group_message_page, insert_message as insert_group_message,
+        invite as invite_to_group, remove_member,
+    },
+    keys::handlers::{
+        consume_bundle, get_prekey_count, post_one_time_prekeys, put_identity_key,
+        put_signed_prekey,
+    },
+    users::{
+        deletion,
+        handlers::{
+            accept, block, cancel, cancel_account_deletion, decline, delete_account,
+            filtered_search, get_blocked, get_chat_keys, get_device_keys, get_message_page,
+            get_receipts, invite, put_device_key, rotate_key, search, set_message_ttl, sync,
+            unblock, unfriend, update_receipt,
+        },
+    },
+};
+use rocket::{fairing::AdHoc, figment::Figment, routes, Build, Rocket};
+use rocket_db_pools::Database;
+
+pub mod attachments;
 pub mod auth;
 pub mod chat;
 pub mod config;
 pub mod db;
+pub mod events;
+pub mod groups;
+pub mod keys;
 pub mod users;
 pub mod utils;
 
 pub trait Validate {
     fn validate(&self) -> bool;
 }
+
+/// Every route and fairing of the server, configured by `figment`.
+pub fn build(figment: Figment) -> Rocket<Build> {
+    rocket::custom(figment)
+        .attach(AdHoc::config::<Config>())
+        .attach(Db::init())
+        .attach(auth::fairing())
+        .manage(Hub::default())
+        .attach(listener::fairing())
+        .attach(attachments::fairing())
+        .attach(purge::fairing())
+        .attach(deletion::fairing())
+        .mount("/", routes![jwks])
+        .mount(
+            "/auth",
+            routes![
+                signup,
+                signin,
+                signin_second_factor,
+                refresh,
+                logout,
+                get_sessions,
+                revoke_session,
+                revoke_other_sessions,
+                enroll_totp,
+                confirm_totp,
+                disable_totp,
+                change_password,
+                recover_account,
+                regenerate_recovery_codes
+            ],
+        )
+        .mount(
+            "/users",
+            routes![
+                invite,
+                accept,
+                decline,
+                cancel,
+                unfriend,
+                block,
+                unblock,
+                get_blocked,
+                rotate_key,
+                set_message_ttl,
+                get_chat_keys,
+                put_device_key,
+                get_device_keys,
+                filtered_search,
+                search,
+                get_message_page,
+                sync,
+                get_receipts,
+                update_receipt,
+                delete_account,
+                cancel_account_deletion
+            ],
+        )
+        .mount("/chats", routes![get_chats])
+        .mount(
+            "/messages",
+            routes![
+                insert_message,
+                edit_message,
+                delete_message,
+                put_reaction,
+                delete_reaction,
+                get_replies
+            ],
+        )
+        .mount(
+            "/groups",
+            routes![
+                create_group,
+                get_groups,
+                get_invites,
+                get_members,
+                invite_to_group,
+                accept_group,
+                change_role,
+                remove_member,
+                insert_group_message,
+                get_group_message_page
+            ],
+        )
+        .mount(
+            "/keys",
+            routes![
+                put_identity_key,
+                put_signed_prekey,
+                post_one_time_prekeys,
+                get_prekey_count,
+                consume_bundle
+            ],
+        )
+        .mount("/attachments", routes![upload, download])
+        .mount("/ws", routes![subscribe])
+}
@@ -1,10 +1,11 @@
 use crate::Validate;
 use rocket::serde::{uuid::Uuid, Deserialize, Serialize};
 
+pub mod deletion;
 pub mod handlers;
 mod repo;
 
-mod option_hex {
+pub(crate) mod option_hex {
     use rocket::serde::{de::Error, Deserialize, Deserializer, Serializer};
 
     pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error>
@@ -16,7 +17,7 @@ mod option_hex {
                 return Ok(None);
             }
 
-            hex::decode(s).map(|v| Some(v)).or(Err(D::Error::custom(
+            hex::decode(s).map(Some).or(Err(D::Error::custom(
                 "Failed to deserialize bytes from hex string",
             )))
         })?
@@ -49,6 +50,55 @@ pub struct Chat {
     #[serde(with = "option_hex")]
     recipient_public_key: Option<Vec<u8>>,
     created_at: sqlx::types::chrono::NaiveDateTime,
+
+    #[serde(with = "option_hex")]
+    sender_key_signature: Option<Vec<u8>>,
+
+    #[serde(with = "option_hex")]
+    recipient_key_signature: Option<Vec<u8>>,
+    key_version: i32,
+    message_ttl_sec: Option<i32>,
+    ttl_after_read: bool,
+    last_seq: i32,
+}
+
+/// Both public keys of a chat as they stood at `version`.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct ChatKey {
+    chat_id: i32,
+    version: i32,
+
+    #[serde(with = "hex::serde")]
+    sender_public_key: Vec<u8>,
+
+    #[serde(with = "option_hex")]
+    sender_key_signature: Option<Vec<u8>>,
+
+    #[serde(with = "hex::serde")]
+    recipient_public_key: Vec<u8>,
+
+    #[serde(with = "option_hex")]
+    recipient_key_signature: Option<Vec<u8>>,
+    created_at: sqlx::types::chrono::NaiveDateTime,
+}
+
+/// Public key a single device of either party uses in a chat.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceKey {
+    chat_id: i32,
+    device_id: Uuid,
+    user_id: Uuid,
+
+    #[serde(with = "hex::serde")]
+    public_key: Vec<u8>,
+
+    #[serde(with = "hex::serde")]
+    signature: Vec<u8>,
+    created_at: sqlx::types::chrono::NaiveDateTime,
 }
 
 #[derive(Deserialize)]
@@ -56,15 +106,72 @@ pub struct Chat {
 #[serde(rename_all = "camelCase")]
 pub struct PublicKey {
     #[serde(with = "hex::serde")]
-    public_key: Vec<u8>,
+    pub public_key: Vec<u8>,
+
+    /// Ed25519 signature of `public_key` by the uploader's identity key.
+    #[serde(with = "hex::serde")]
+    pub signature: Vec<u8>,
 }
 
 impl Validate for PublicKey {
     fn validate(&self) -> bool {
-        self.public_key.len() == 32
+        self.public_key.len() == 32 && self.signature.len() == 64
+    }
+}
+
+/// How long messages of a chat live. Without `ttl_sec` they never expire;
+/// with `after_read` the clock starts when the recipient reads them.
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct MessageTtl {
+    pub ttl_sec: Option<i32>,
+
+    #[serde(default)]
+    pub after_read: bool,
+}
+
+impl Validate for MessageTtl {
+    fn validate(&self) -> bool {
+        self.ttl_sec.is_none_or(|t| t > 0)
     }
 }
 
+/// Ways a chat row can go away. Each deletes the chat outright, so the pair
+/// can invite each other again afterwards.
+pub enum ChatRemoval {
+    /// The recipient turns down a pending invite.
+    Decline,
+    /// The sender withdraws a pending invite.
+    Cancel,
+    /// Either friend ends an accepted chat.
+    Unfriend,
+}
+
+/// A chat that was removed. Its messages, attachments, keys and receipts
+/// are deleted with it for both parties.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct RemovedChat {
+    chat_id: i32,
+    sender_id: Uuid,
+    recipient_id: Uuid,
+    deleted_messages: i64,
+
+    #[serde(skip)]
+    pub attachment_ids: Vec<Uuid>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct Block {
+    blocker_id: Uuid,
+    blocked_id: Uuid,
+    created_at: sqlx::types::chrono::NaiveDateTime,
+}
+
 #[derive(Serialize)]
 #[serde(crate = "rocket::serde")]
 #[serde(rename_all = "camelCase")]
@@ -73,3 +180,23 @@ pub struct User {
     username: String,
     created_at: sqlx::types::chrono::NaiveDateTime,
 }
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct AccountDeletion {
+    pub password: String,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "camelCase")]
+pub struct DeletionSchedule {
+    /// Until then the deletion can be cancelled with `DELETE /users/me/deletion`.
+    pub scheduled_for: sqlx::types::chrono::NaiveDateTime,
+}
+
+/// What is left to clean up outside the database once an account is gone.
+struct DeletedAccount {
+    user_id: Uuid,
+    attachment_ids: Vec<Uuid>,
+}
@@ -0,0 +1,78 @@
+use super::repo;
+use crate::{
+    attachments::storage::{self, Storage},
+    config::Config,
+    db::Db,
+};
+use rocket::{fairing::AdHoc, tokio::time};
+use rocket_db_pools::Database;
+use sqlx::PgPool;
+use std::{sync::Arc, time::Duration};
+
+/// Spawns a task that carries out account deletions whose grace period is
+/// over, checking every `purge_interval_sec`.
+pub fn fairing() -> AdHoc {
+    AdHoc::on_liftoff("Account Deletion", |rocket| {
+        Box::pin(async move {
+            let pool = PgPool::clone(Db::fetch(rocket).expect("database is attached"));
+            let storage = Arc::clone(
+                rocket
+                    .state::<Arc<dyn Storage>>()
+                    .expect("storage is attached"),
+            );
+            let config = rocket.state::<Config>().expect("config is attached");
+
+            rocket::tokio::spawn(delete_due(
+                pool,
+                storage,
+                Duration::from_secs(config.purge_interval_sec),
+                config.pseudonymize_deleted_messages,
+            ));
+        })
+    })
+}
+
+async fn delete_due(pool: PgPool, storage: Arc<dyn Storage>, period: Duration, pseudonymize: bool) {
+    let mut interval = time::interval(period);
+
+    loop {
+        interval.tick().await;
+
+        let mut db = match pool.acquire().await {
+            Ok(db) => db,
+            Err(sqlx::Error::PoolClosed) => break,
+            Err(e) => {
+                rocket::error!("failed to delete accounts: {}", e);
+                continue;
+            }
+        };
+
+        let user_ids = match repo::get_due_deletions(&mut db).await {
+            Ok(ids) => ids,
+            Err(e) => {
+                rocket::error!("failed to delete accounts: {}", e);
+                continue;
+            }
+        };
+
+        for user_id in user_ids {
+            let result = match pseudonymize {
+                true => repo::pseudonymize_account(&mut db, user_id).await,
+                false => repo::delete_account(&mut db, user_id).await,
+            };
+
+            let account = match result {
+                Ok(Some(a)) => a,
+                Ok(None) => continue,
+                Err(e) => {
+                    rocket::error!("failed to delete account {}: {}", user_id, e);
+                    continue;
+                }
+            };
+
+            rocket::info!("deleted account {}", account.user_id);
+
+            storage::delete_all(&storage, &account.attachment_ids).await;
+        }
+    }
+}
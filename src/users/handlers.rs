@@ -1,8 +1,21 @@
-use super::{Chat, PublicKey, User};
-use crate::{auth::AuthenticatedUser, chat::StoredMessage, db::Db, users::repo, Validate};
-use rocket::{http::Status, serde::json::Json, FromForm, FromFormField};
+use super::{
+    AccountDeletion, Block, Chat, ChatKey, ChatRemoval, DeletionSchedule, DeviceKey, MessageTtl,
+    PublicKey, RemovedChat, User,
+};
+use crate::{
+    attachments::storage::{self, Storage},
+    auth::{password, AuthenticatedDevice, AuthenticatedUser},
+    chat::{ChatRef, Cursor, MessageList, Receipt, ReceiptUpdate, SyncPage},
+    config::Config,
+    db::Db,
+    keys::{self, KeyUploadError},
+    users::repo,
+    Validate,
+};
+use rocket::{http::Status, serde::json::Json, FromForm, FromFormField, State};
 use rocket_db_pools::Connection;
 use sqlx::types::Uuid;
+use std::sync::Arc;
 
 #[rocket::post("/<recipient_id>/invite", data = "<body>")]
 pub async fn invite(
@@ -10,35 +23,189 @@ pub async fn invite(
     body: Json<PublicKey>,
     recipient_id: Uuid,
     sender: AuthenticatedUser,
-) -> Result<Json<Chat>, Status> {
+) -> Result<Json<Chat>, KeyUploadError> {
     if !body.validate() {
-        return Err(Status::UnprocessableEntity);
+        return Err(Status::UnprocessableEntity.into());
     }
 
-    let chat = repo::invite_user(&mut db, sender.id, recipient_id, &body.public_key)
+    keys::verify_signed_key(&mut db, sender.id, &body.public_key, &body.signature).await?;
+
+    let chat = repo::invite_user(
+        &mut db,
+        sender.id,
+        recipient_id,
+        &body.public_key,
+        &body.signature,
+    )
+    .await
+    .map_err(|e| match e.as_database_error() {
+        Some(e) if e.is_unique_violation() => Status::Conflict,
+        Some(e) if e.is_foreign_key_violation() => Status::NotFound,
+        Some(e) if e.is_check_violation() => Status::UnprocessableEntity,
+        _ => Status::InternalServerError,
+    })?
+    .ok_or(Status::NotFound)?;
+
+    Ok(Json(chat))
+}
+
+#[rocket::post("/<sender_id>/accept", data = "<body>")]
+pub async fn accept(
+    mut db: Connection<Db>,
+    body: Json<PublicKey>,
+    sender_id: Uuid,
+    recipient: AuthenticatedUser,
+) -> Result<Json<Chat>, KeyUploadError> {
+    if !body.validate() {
+        return Err(Status::UnprocessableEntity.into());
+    }
+
+    keys::verify_signed_key(&mut db, recipient.id, &body.public_key, &body.signature).await?;
+
+    let chat = repo::accept_user(
+        &mut db,
+        sender_id,
+        recipient.id,
+        &body.public_key,
+        &body.signature,
+    )
+    .await
+    .map_err(|e| match e.as_database_error() {
+        Some(e) if e.is_check_violation() => Status::UnprocessableEntity,
+        _ => Status::InternalServerError,
+    })?
+    .ok_or(Status::NotFound)?;
+
+    Ok(Json(chat))
+}
+
+/// Turns down a pending invite from `sender_id`.
+#[rocket::post("/<sender_id>/decline")]
+pub async fn decline(
+    mut db: Connection<Db>,
+    sender_id: Uuid,
+    recipient: AuthenticatedUser,
+    storage: &State<Arc<dyn Storage>>,
+) -> Result<Json<RemovedChat>, Status> {
+    remove_chat(
+        &mut db,
+        recipient.id,
+        sender_id,
+        ChatRemoval::Decline,
+        storage,
+    )
+    .await
+}
+
+/// Withdraws a pending invite to `recipient_id`.
+#[rocket::delete("/<recipient_id>/invite")]
+pub async fn cancel(
+    mut db: Connection<Db>,
+    recipient_id: Uuid,
+    sender: AuthenticatedUser,
+    storage: &State<Arc<dyn Storage>>,
+) -> Result<Json<RemovedChat>, Status> {
+    remove_chat(
+        &mut db,
+        sender.id,
+        recipient_id,
+        ChatRemoval::Cancel,
+        storage,
+    )
+    .await
+}
+
+/// Ends the chat with `friend_id`, deleting its whole history for both.
+#[rocket::delete("/<friend_id>/friendship")]
+pub async fn unfriend(
+    mut db: Connection<Db>,
+    friend_id: Uuid,
+    user: AuthenticatedUser,
+    storage: &State<Arc<dyn Storage>>,
+) -> Result<Json<RemovedChat>, Status> {
+    remove_chat(&mut db, user.id, friend_id, ChatRemoval::Unfriend, storage).await
+}
+
+async fn remove_chat(
+    db: &mut Connection<Db>,
+    user_id: Uuid,
+    friend_id: Uuid,
+    removal: ChatRemoval,
+    storage: &State<Arc<dyn Storage>>,
+) -> Result<Json<RemovedChat>, Status> {
+    let removed = repo::remove_chat(db, user_id, friend_id, removal)
+        .await
+        .or(Err(Status::InternalServerError))?
+        .ok_or(Status::NotFound)?;
+
+    storage::delete_all(storage, &removed.attachment_ids).await;
+
+    Ok(Json(removed))
+}
+
+#[rocket::post("/<user_id>/block")]
+pub async fn block(
+    mut db: Connection<Db>,
+    user_id: Uuid,
+    user: AuthenticatedUser,
+) -> Result<Json<Block>, Status> {
+    let block = repo::block_user(&mut db, user.id, user_id)
         .await
         .map_err(|e| match e.as_database_error() {
-            Some(e) if e.is_unique_violation() => Status::Conflict,
             Some(e) if e.is_foreign_key_violation() => Status::NotFound,
             Some(e) if e.is_check_violation() => Status::UnprocessableEntity,
             _ => Status::InternalServerError,
         })?;
 
-    Ok(Json(chat))
+    Ok(Json(block))
 }
 
-#[rocket::post("/<sender_id>/accept", data = "<body>")]
-pub async fn accept(
+#[rocket::delete("/<user_id>/block")]
+pub async fn unblock(
+    mut db: Connection<Db>,
+    user_id: Uuid,
+    user: AuthenticatedUser,
+) -> Result<Json<Block>, Status> {
+    let block = repo::unblock_user(&mut db, user.id, user_id)
+        .await
+        .or(Err(Status::InternalServerError))?
+        .ok_or(Status::NotFound)?;
+
+    Ok(Json(block))
+}
+
+#[rocket::get("/blocked")]
+pub async fn get_blocked(
+    mut db: Connection<Db>,
+    user: AuthenticatedUser,
+) -> Result<Json<Vec<User>>, Status> {
+    let users = repo::get_blocked_users(&mut db, user.id)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    Ok(Json(users))
+}
+
+#[rocket::put("/<friend_id>/key", data = "<body>")]
+pub async fn rotate_key(
     mut db: Connection<Db>,
     body: Json<PublicKey>,
-    sender_id: Uuid,
-    recipient: AuthenticatedUser,
-) -> Result<Json<Chat>, Status> {
+    friend_id: Uuid,
+    user: AuthenticatedUser,
+) -> Result<Json<Chat>, KeyUploadError> {
     if !body.validate() {
-        return Err(Status::UnprocessableEntity);
+        return Err(Status::UnprocessableEntity.into());
     }
 
-    let chat = repo::accept_user(&mut db, sender_id, recipient.id, &body.public_key)
+    keys::verify_signed_key(&mut db, user.id, &body.public_key, &body.signature).await?;
+
+    let membership =
+        crate::chat::repo::get_membership(&mut db, user.id, ChatRef::Friend(friend_id))
+            .await
+            .or(Err(Status::InternalServerError))?
+            .ok_or(Status::NotFound)?;
+
+    let chat = repo::rotate_key(&mut db, &membership, &body.public_key, &body.signature)
         .await
         .map_err(|e| match e.as_database_error() {
             Some(e) if e.is_check_violation() => Status::UnprocessableEntity,
@@ -49,6 +216,101 @@ pub async fn accept(
     Ok(Json(chat))
 }
 
+#[rocket::put("/<friend_id>/ttl", data = "<body>")]
+pub async fn set_message_ttl(
+    mut db: Connection<Db>,
+    body: Json<MessageTtl>,
+    friend_id: Uuid,
+    user: AuthenticatedUser,
+) -> Result<Json<Chat>, Status> {
+    if !body.validate() {
+        return Err(Status::UnprocessableEntity);
+    }
+
+    let membership =
+        crate::chat::repo::get_membership(&mut db, user.id, ChatRef::Friend(friend_id))
+            .await
+            .or(Err(Status::InternalServerError))?
+            .ok_or(Status::NotFound)?;
+
+    let chat = repo::set_message_ttl(&mut db, membership.chat_id, body.ttl_sec, body.after_read)
+        .await
+        .or(Err(Status::InternalServerError))?
+        .ok_or(Status::NotFound)?;
+
+    Ok(Json(chat))
+}
+
+#[rocket::get("/<friend_id>/keys")]
+pub async fn get_chat_keys(
+    mut db: Connection<Db>,
+    friend_id: Uuid,
+    user: AuthenticatedUser,
+) -> Result<Json<Vec<ChatKey>>, Status> {
+    let membership =
+        crate::chat::repo::get_membership(&mut db, user.id, ChatRef::Friend(friend_id))
+            .await
+            .or(Err(Status::InternalServerError))?
+            .ok_or(Status::NotFound)?;
+
+    let keys = repo::get_chat_keys(&mut db, membership.chat_id)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    Ok(Json(keys))
+}
+
+#[rocket::put("/<friend_id>/device-key", data = "<body>")]
+pub async fn put_device_key(
+    mut db: Connection<Db>,
+    body: Json<PublicKey>,
+    friend_id: Uuid,
+    device: AuthenticatedDevice,
+) -> Result<Json<DeviceKey>, KeyUploadError> {
+    if !body.validate() {
+        return Err(Status::UnprocessableEntity.into());
+    }
+
+    keys::verify_signed_key(&mut db, device.user.id, &body.public_key, &body.signature).await?;
+
+    let membership =
+        crate::chat::repo::get_membership(&mut db, device.user.id, ChatRef::Friend(friend_id))
+            .await
+            .or(Err(Status::InternalServerError))?
+            .ok_or(Status::NotFound)?;
+
+    let key = repo::upsert_device_key(
+        &mut db,
+        membership.chat_id,
+        device.id,
+        &body.public_key,
+        &body.signature,
+    )
+    .await
+    .or(Err(Status::InternalServerError))?;
+
+    Ok(Json(key))
+}
+
+#[rocket::get("/<friend_id>/device-keys")]
+pub async fn get_device_keys(
+    mut db: Connection<Db>,
+    friend_id: Uuid,
+    user: AuthenticatedUser,
+) -> Result<Json<Vec<DeviceKey>>, Status> {
+    let membership =
+        crate::chat::repo::get_membership(&mut db, user.id, ChatRef::Friend(friend_id))
+            .await
+            .or(Err(Status::InternalServerError))?
+            .ok_or(Status::NotFound)?;
+
+    let keys = repo::get_device_keys(&mut db, membership.chat_id)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    Ok(Json(keys))
+}
+
 #[derive(FromFormField)]
 pub enum UserFilter {
     Invited,
@@ -85,34 +347,241 @@ pub async fn search(
     Ok(Json(users))
 }
 
+const DEFAULT_MESSAGE_PAGE: i64 = 50;
+const MAX_MESSAGE_PAGE: i64 = 100;
+
+/// At most one of `before`, `after` and `around` may be given. Without any,
+/// the latest messages are returned.
 #[derive(FromForm)]
 pub struct MessagePage {
-    pub start_timestamp: i64,
-    pub start_id: i32,
-    pub limit: i64,
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub around: Option<String>,
+    pub limit: Option<i64>,
 }
 
 #[rocket::get("/<friend_id>/messages?<page..>")]
 pub async fn get_message_page(
     mut db: Connection<Db>,
-    user: AuthenticatedUser,
+    device: AuthenticatedDevice,
     friend_id: Uuid,
     page: MessagePage,
-) -> Result<Json<Vec<StoredMessage>>, Status> {
-    let start_timestamp = chrono::DateTime::from_timestamp(page.start_timestamp, 0)
-        .ok_or(Status::UnprocessableEntity)?
-        .naive_utc();
+) -> Result<Json<MessageList>, Status> {
+    let limit = page
+        .limit
+        .unwrap_or(DEFAULT_MESSAGE_PAGE)
+        .clamp(1, MAX_MESSAGE_PAGE);
+    let parse = |c: Option<String>| {
+        c.map(|c| c.parse::<Cursor>())
+            .transpose()
+            .or(Err(Status::UnprocessableEntity))
+    };
+    let device_id = device.id;
+
+    // The last two flags tell whether there are messages past either end of
+    // the page regardless of what the queries below find.
+    let (older_limit, newer_limit, before, after, more_older, more_newer) =
+        match (parse(page.before)?, parse(page.after)?, parse(page.around)?) {
+            (before, None, None) => (limit, 0, before, None, false, before.is_some()),
+            (None, Some(after), None) => (0, limit, None, Some(after), true, false),
+            // Ids are integers, so bumping the id makes the older half
+            // include the message at the cursor itself.
+            (None, None, Some(around)) => (
+                limit - limit / 2,
+                limit / 2,
+                Some(Cursor {
+                    id: around.id.saturating_add(1),
+                    ..around
+                }),
+                Some(around),
+                false,
+                false,
+            ),
+            _ => return Err(Status::UnprocessableEntity),
+        };
+
+    let membership =
+        crate::chat::repo::get_membership(&mut db, device.user.id, ChatRef::Friend(friend_id))
+            .await
+            .or(Err(Status::InternalServerError))?
+            .ok_or(Status::NotFound)?;
+
+    // One extra row on each side tells whether there is more to page through.
+    let mut older = match older_limit {
+        0 => Vec::new(),
+        _ => crate::chat::repo::get_messages_before(
+            &mut db,
+            membership.chat_id,
+            device_id,
+            before,
+            older_limit + 1,
+        )
+        .await
+        .or(Err(Status::InternalServerError))?,
+    };
+    let mut newer = match after {
+        Some(after) if newer_limit > 0 => crate::chat::repo::get_messages_after(
+            &mut db,
+            membership.chat_id,
+            device_id,
+            after,
+            newer_limit + 1,
+        )
+        .await
+        .or(Err(Status::InternalServerError))?,
+        _ => Vec::new(),
+    };
+
+    let more_older = older.len() as i64 > older_limit || more_older;
+    let more_newer = newer.len() as i64 > newer_limit || more_newer;
+    older.truncate(older_limit as usize);
+    newer.truncate(newer_limit as usize);
+
+    let messages: Vec<_> = newer.into_iter().rev().chain(older).collect();
+    let next_cursor = messages.last().filter(|_| more_older).map(Cursor::from);
+    let prev_cursor = messages.first().filter(|_| more_newer).map(Cursor::from);
+
+    let messages = crate::chat::repo::annotate_messages(&mut db, messages)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    Ok(Json(MessageList {
+        messages,
+        next_cursor,
+        prev_cursor,
+    }))
+}
+
+#[rocket::get("/<friend_id>/sync?<since>&<limit>")]
+pub async fn sync(
+    mut db: Connection<Db>,
+    device: AuthenticatedDevice,
+    friend_id: Uuid,
+    since: i32,
+    limit: Option<i64>,
+) -> Result<Json<SyncPage>, Status> {
+    let limit = limit
+        .unwrap_or(DEFAULT_MESSAGE_PAGE)
+        .clamp(1, MAX_MESSAGE_PAGE);
 
-    let messages = crate::chat::repo::get_message_page(
+    let membership =
+        crate::chat::repo::get_membership(&mut db, device.user.id, ChatRef::Friend(friend_id))
+            .await
+            .or(Err(Status::InternalServerError))?
+            .ok_or(Status::NotFound)?;
+
+    // Read first, so messages sent in between show up on the next sync
+    // rather than being reported past `last_seq`.
+    let last_seq = crate::chat::repo::get_last_seq(&mut db, membership.chat_id)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    let mut messages = crate::chat::repo::get_messages_since(
         &mut db,
-        user.id,
-        friend_id,
-        start_timestamp,
-        page.start_id,
-        page.limit,
+        membership.chat_id,
+        device.id,
+        since,
+        limit + 1,
     )
     .await
     .or(Err(Status::InternalServerError))?;
 
-    Ok(Json(messages))
+    messages.retain(|m| m.seq <= last_seq);
+    let has_more = messages.len() as i64 > limit;
+    messages.truncate(limit as usize);
+
+    let messages = crate::chat::repo::annotate_messages(&mut db, messages)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    Ok(Json(SyncPage {
+        messages,
+        last_seq,
+        has_more,
+    }))
+}
+
+#[rocket::get("/<friend_id>/receipts")]
+pub async fn get_receipts(
+    mut db: Connection<Db>,
+    user: AuthenticatedUser,
+    friend_id: Uuid,
+) -> Result<Json<Vec<Receipt>>, Status> {
+    let membership =
+        crate::chat::repo::get_membership(&mut db, user.id, ChatRef::Friend(friend_id))
+            .await
+            .or(Err(Status::InternalServerError))?
+            .ok_or(Status::NotFound)?;
+
+    let receipts = crate::chat::repo::get_receipts(&mut db, membership.chat_id)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    Ok(Json(receipts))
+}
+
+#[rocket::put("/<friend_id>/receipts", data = "<body>")]
+pub async fn update_receipt(
+    mut db: Connection<Db>,
+    body: Json<ReceiptUpdate>,
+    user: AuthenticatedUser,
+    friend_id: Uuid,
+) -> Result<Json<Receipt>, Status> {
+    if !body.validate() {
+        return Err(Status::UnprocessableEntity);
+    }
+
+    let membership =
+        crate::chat::repo::get_membership(&mut db, user.id, ChatRef::Friend(friend_id))
+            .await
+            .or(Err(Status::InternalServerError))?
+            .ok_or(Status::NotFound)?;
+
+    let receipt = crate::chat::repo::update_receipt(
+        &mut db,
+        &membership,
+        body.delivered_up_to,
+        body.read_up_to,
+    )
+    .await
+    .or(Err(Status::InternalServerError))?;
+
+    Ok(Json(receipt))
+}
+
+/// Schedules the deletion of the caller's account, which takes the password
+/// again. Until the grace period is over it can still be cancelled.
+#[rocket::delete("/me", data = "<body>")]
+pub async fn delete_account(
+    mut db: Connection<Db>,
+    body: Json<AccountDeletion>,
+    user: AuthenticatedUser,
+    config: &State<Config>,
+) -> Result<Json<DeletionSchedule>, Status> {
+    if !password::check(&mut db, config, user.id, &body.password).await? {
+        return Err(Status::Forbidden);
+    }
+
+    let schedule =
+        repo::schedule_deletion(&mut db, user.id, config.account_deletion_grace_sec as f64)
+            .await
+            .or(Err(Status::InternalServerError))?
+            .ok_or(Status::NotFound)?;
+
+    Ok(Json(schedule))
+}
+
+#[rocket::delete("/me/deletion")]
+pub async fn cancel_account_deletion(
+    mut db: Connection<Db>,
+    user: AuthenticatedUser,
+) -> Result<(), Status> {
+    let cancelled = repo::cancel_deletion(&mut db, user.id)
+        .await
+        .or(Err(Status::InternalServerError))?;
+
+    match cancelled {
+        true => Ok(()),
+        false => Err(Status::NotFound),
+    }
 }
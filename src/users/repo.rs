@@ -1,24 +1,34 @@
-use super::{handlers::UserFilter, Chat, User};
-use sqlx::{types::Uuid, PgConnection};
+use super::{
+    handlers::UserFilter, Block, Chat, ChatKey, ChatRemoval, DeletedAccount, DeletionSchedule,
+    DeviceKey, RemovedChat, User,
+};
+use crate::{
+    chat::Membership,
+    events::{self, Notification},
+};
+use sqlx::{types::Uuid, Connection, PgConnection};
 
 pub async fn invite_user(
     db: &mut PgConnection,
     sender_id: Uuid,
     recipient_id: Uuid,
     sender_public_key: &[u8],
-) -> Result<Chat, sqlx::Error> {
+    sender_key_signature: &[u8],
+) -> Result<Option<Chat>, sqlx::Error> {
     sqlx::query_as!(
         Chat,
         r#"
-        INSERT INTO chats (sender_id, recipient_id, sender_public_key)
-        VALUES ($1, $2, $3)
+        INSERT INTO chats (sender_id, recipient_id, sender_public_key, sender_key_signature)
+        SELECT $1, $2, $3, $4
+        WHERE NOT is_blocked($1, $2)
         RETURNING *;
         "#,
         sender_id,
         recipient_id,
-        sender_public_key
+        sender_public_key,
+        sender_key_signature
     )
-    .fetch_one(&mut *db)
+    .fetch_optional(&mut *db)
     .await
 }
 
@@ -27,24 +37,293 @@ pub async fn accept_user(
     sender_id: Uuid,
     recipient_id: Uuid,
     recipient_public_key: &[u8],
+    recipient_key_signature: &[u8],
 ) -> Result<Option<Chat>, sqlx::Error> {
-    sqlx::query_as!(
+    let mut tx = db.begin().await?;
+
+    let chat = sqlx::query_as!(
         Chat,
         r#"
         UPDATE chats
         SET
-            recipient_public_key = $3
+            recipient_public_key = $3,
+            recipient_key_signature = $4
         WHERE sender_id = $1 AND recipient_id = $2 AND recipient_public_key IS NULL
+            AND NOT is_blocked($1, $2)
         RETURNING *;
         "#,
         sender_id,
         recipient_id,
-        recipient_public_key
+        recipient_public_key,
+        recipient_key_signature
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    if let Some(ref c) = chat {
+        insert_chat_key(&mut tx, c.id).await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(chat)
+}
+
+/// Deletes the chat between the two users as `removal` allows. Counts and
+/// attachment ids are read off the snapshot from before the cascade.
+pub async fn remove_chat(
+    db: &mut PgConnection,
+    user_id: Uuid,
+    friend_id: Uuid,
+    removal: ChatRemoval,
+) -> Result<Option<RemovedChat>, sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    let removed = match removal {
+        ChatRemoval::Decline => {
+            sqlx::query_as!(
+                RemovedChat,
+                r#"
+                DELETE FROM chats c
+                WHERE sender_id = $2 AND recipient_id = $1 AND recipient_public_key IS NULL
+                RETURNING
+                    id AS chat_id,
+                    sender_id,
+                    recipient_id,
+                    (SELECT count(*) FROM messages WHERE chat_id = c.id) AS "deleted_messages!",
+                    ARRAY(SELECT id FROM attachments WHERE chat_id = c.id) AS "attachment_ids!";
+                "#,
+                user_id,
+                friend_id
+            )
+            .fetch_optional(&mut *tx)
+            .await?
+        }
+        ChatRemoval::Cancel => {
+            sqlx::query_as!(
+                RemovedChat,
+                r#"
+                DELETE FROM chats c
+                WHERE sender_id = $1 AND recipient_id = $2 AND recipient_public_key IS NULL
+                RETURNING
+                    id AS chat_id,
+                    sender_id,
+                    recipient_id,
+                    (SELECT count(*) FROM messages WHERE chat_id = c.id) AS "deleted_messages!",
+                    ARRAY(SELECT id FROM attachments WHERE chat_id = c.id) AS "attachment_ids!";
+                "#,
+                user_id,
+                friend_id
+            )
+            .fetch_optional(&mut *tx)
+            .await?
+        }
+        ChatRemoval::Unfriend => {
+            sqlx::query_as!(
+                RemovedChat,
+                r#"
+                DELETE FROM chats c
+                WHERE (
+                    (sender_id = $1 AND recipient_id = $2) OR
+                    (sender_id = $2 AND recipient_id = $1)
+                ) AND recipient_public_key IS NOT NULL
+                RETURNING
+                    id AS chat_id,
+                    sender_id,
+                    recipient_id,
+                    (SELECT count(*) FROM messages WHERE chat_id = c.id) AS "deleted_messages!",
+                    ARRAY(SELECT id FROM attachments WHERE chat_id = c.id) AS "attachment_ids!";
+                "#,
+                user_id,
+                friend_id
+            )
+            .fetch_optional(&mut *tx)
+            .await?
+        }
+    };
+
+    if let Some(ref r) = removed {
+        let notification = Notification::ChatRemoved {
+            chat_id: r.chat_id,
+            user_ids: [r.sender_id, r.recipient_id],
+        };
+
+        events::repo::notify(&mut tx, &notification).await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(removed)
+}
+
+/// Replaces the member's side of the chat and bumps its key version. The
+/// previous keys stay in `chat_keys`.
+pub async fn rotate_key(
+    db: &mut PgConnection,
+    membership: &Membership,
+    public_key: &[u8],
+    key_signature: &[u8],
+) -> Result<Option<Chat>, sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    let chat = sqlx::query_as!(
+        Chat,
+        r#"
+        UPDATE chats
+        SET
+            sender_public_key = CASE WHEN sender_id = $1 THEN $3 ELSE sender_public_key END,
+            sender_key_signature =
+                CASE WHEN sender_id = $1 THEN $4 ELSE sender_key_signature END,
+            recipient_public_key =
+                CASE WHEN recipient_id = $1 THEN $3 ELSE recipient_public_key END,
+            recipient_key_signature =
+                CASE WHEN recipient_id = $1 THEN $4 ELSE recipient_key_signature END,
+            key_version = key_version + 1
+        WHERE id = $2
+        RETURNING *;
+        "#,
+        membership.user_id,
+        membership.chat_id,
+        public_key,
+        key_signature
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    if let Some(ref c) = chat {
+        insert_chat_key(&mut tx, c.id).await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(chat)
+}
+
+/// Applies to messages sent from now on; either participant may change it.
+pub async fn set_message_ttl(
+    db: &mut PgConnection,
+    chat_id: i32,
+    ttl_sec: Option<i32>,
+    after_read: bool,
+) -> Result<Option<Chat>, sqlx::Error> {
+    sqlx::query_as!(
+        Chat,
+        r#"
+        UPDATE chats
+        SET
+            message_ttl_sec = $2,
+            ttl_after_read = $3
+        WHERE id = $1
+        RETURNING *;
+        "#,
+        chat_id,
+        ttl_sec,
+        after_read
     )
     .fetch_optional(&mut *db)
     .await
 }
 
+async fn insert_chat_key(db: &mut PgConnection, chat_id: i32) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO chat_keys (
+            chat_id,
+            version,
+            sender_public_key,
+            sender_key_signature,
+            recipient_public_key,
+            recipient_key_signature
+        )
+        SELECT
+            id,
+            key_version,
+            sender_public_key,
+            sender_key_signature,
+            recipient_public_key,
+            recipient_key_signature
+        FROM chats
+        WHERE id = $1 AND recipient_public_key IS NOT NULL;
+        "#,
+        chat_id
+    )
+    .execute(&mut *db)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_chat_keys(
+    db: &mut PgConnection,
+    chat_id: i32,
+) -> Result<Vec<ChatKey>, sqlx::Error> {
+    sqlx::query_as!(
+        ChatKey,
+        r"SELECT * FROM chat_keys WHERE chat_id = $1 ORDER BY version DESC;",
+        chat_id
+    )
+    .fetch_all(&mut *db)
+    .await
+}
+
+pub async fn upsert_device_key(
+    db: &mut PgConnection,
+    chat_id: i32,
+    device_id: Uuid,
+    public_key: &[u8],
+    signature: &[u8],
+) -> Result<DeviceKey, sqlx::Error> {
+    sqlx::query_as!(
+        DeviceKey,
+        r#"
+        WITH k AS (
+            INSERT INTO chat_device_keys (chat_id, device_id, public_key, signature)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (chat_id, device_id) DO UPDATE
+            SET
+                public_key = EXCLUDED.public_key,
+                signature = EXCLUDED.signature,
+                created_at = NOW()
+            RETURNING *
+        )
+        SELECT
+            k.chat_id AS "chat_id!",
+            k.device_id AS "device_id!",
+            d.user_id,
+            k.public_key AS "public_key!",
+            k.signature AS "signature!",
+            k.created_at AS "created_at!"
+        FROM k
+        JOIN devices d ON d.id = k.device_id;
+        "#,
+        chat_id,
+        device_id,
+        public_key,
+        signature
+    )
+    .fetch_one(&mut *db)
+    .await
+}
+
+pub async fn get_device_keys(
+    db: &mut PgConnection,
+    chat_id: i32,
+) -> Result<Vec<DeviceKey>, sqlx::Error> {
+    sqlx::query_as!(
+        DeviceKey,
+        r#"
+        SELECT k.chat_id, k.device_id, d.user_id, k.public_key, k.signature, k.created_at
+        FROM chat_device_keys k
+        JOIN devices d ON d.id = k.device_id
+        WHERE k.chat_id = $1
+        ORDER BY d.user_id, k.created_at;
+        "#,
+        chat_id
+    )
+    .fetch_all(&mut *db)
+    .await
+}
+
 pub async fn filtered_search_users(
     db: &mut PgConnection,
     user_id: Uuid,
@@ -59,7 +338,7 @@ pub async fn filtered_search_users(
                 SELECT u.id, u.username, u.created_at
                 FROM users u
                 JOIN chats c ON c.recipient_id = u.id
-                WHERE c.sender_id = $1 AND u.username %> $2;
+                WHERE c.sender_id = $1 AND u.username %> $2 AND NOT is_blocked($1, u.id);
                 "#,
                 user_id,
                 q
@@ -74,7 +353,8 @@ pub async fn filtered_search_users(
                 SELECT u.id, u.username, u.created_at
                 FROM users u
                 JOIN chats c ON c.sender_id = u.id
-                WHERE c.recipient_id = $1 AND c.recipient_public_key IS NULL AND u.username %> $2;
+                WHERE c.recipient_id = $1 AND c.recipient_public_key IS NULL AND u.username %> $2
+                    AND NOT is_blocked($1, u.id);
                 "#,
                 user_id,
                 q
@@ -88,8 +368,9 @@ pub async fn filtered_search_users(
                 r#"
                 SELECT u.id, u.username, u.created_at
                 FROM users u
-                JOIN chats c ON c.sender_id = u.id OR c.recipient_id = u.id
-                WHERE u.id <> $1 AND c.recipient_public_key IS NOT NULL AND u.username %> $2;
+                JOIN chat_members cm ON cm.friend_id = u.id
+                WHERE cm.user_id = $1 AND cm.accepted AND u.username %> $2
+                    AND NOT is_blocked($1, u.id);
                 "#,
                 user_id,
                 q
@@ -113,7 +394,7 @@ pub async fn filtered_get_users(
                 SELECT u.id, u.username, u.created_at
                 FROM users u
                 JOIN chats c ON c.recipient_id = u.id
-                WHERE c.sender_id = $1;
+                WHERE c.sender_id = $1 AND NOT is_blocked($1, u.id);
                 "#,
                 user_id,
             )
@@ -127,7 +408,8 @@ pub async fn filtered_get_users(
                 SELECT u.id, u.username, u.created_at
                 FROM users u
                 JOIN chats c ON c.sender_id = u.id
-                WHERE c.recipient_id = $1 AND c.recipient_public_key IS NULL;
+                WHERE c.recipient_id = $1 AND c.recipient_public_key IS NULL
+                    AND NOT is_blocked($1, u.id);
                 "#,
                 user_id,
             )
@@ -140,8 +422,8 @@ pub async fn filtered_get_users(
                 r#"
                 SELECT u.id, u.username, u.created_at
                 FROM users u
-                JOIN chats c ON c.sender_id = u.id OR c.recipient_id = u.id
-                WHERE u.id <> $1 AND c.recipient_public_key IS NOT NULL;
+                JOIN chat_members cm ON cm.friend_id = u.id
+                WHERE cm.user_id = $1 AND cm.accepted AND NOT is_blocked($1, u.id);
                 "#,
                 user_id,
             )
@@ -161,7 +443,7 @@ pub async fn search_users(
         r#"
         SELECT id, username, created_at
         FROM users
-        WHERE id <> $1 AND username %> $2;
+        WHERE id <> $1 AND username %> $2 AND NOT is_blocked($1, id);
         "#,
         user_id,
         q,
@@ -169,3 +451,318 @@ pub async fn search_users(
     .fetch_all(&mut *db)
     .await
 }
+
+pub async fn block_user(
+    db: &mut PgConnection,
+    blocker_id: Uuid,
+    blocked_id: Uuid,
+) -> Result<Block, sqlx::Error> {
+    sqlx::query_as!(
+        Block,
+        r#"
+        INSERT INTO blocks (blocker_id, blocked_id)
+        VALUES ($1, $2)
+        ON CONFLICT (blocker_id, blocked_id) DO UPDATE
+        SET blocker_id = EXCLUDED.blocker_id
+        RETURNING *;
+        "#,
+        blocker_id,
+        blocked_id
+    )
+    .fetch_one(&mut *db)
+    .await
+}
+
+pub async fn unblock_user(
+    db: &mut PgConnection,
+    blocker_id: Uuid,
+    blocked_id: Uuid,
+) -> Result<Option<Block>, sqlx::Error> {
+    sqlx::query_as!(
+        Block,
+        r#"
+        DELETE FROM blocks
+        WHERE blocker_id = $1 AND blocked_id = $2
+        RETURNING *;
+        "#,
+        blocker_id,
+        blocked_id
+    )
+    .fetch_optional(&mut *db)
+    .await
+}
+
+pub async fn get_blocked_users(
+    db: &mut PgConnection,
+    blocker_id: Uuid,
+) -> Result<Vec<User>, sqlx::Error> {
+    sqlx::query_as!(
+        User,
+        r#"
+        SELECT u.id, u.username, u.created_at
+        FROM users u
+        JOIN blocks b ON b.blocked_id = u.id
+        WHERE b.blocker_id = $1
+        ORDER BY b.created_at DESC;
+        "#,
+        blocker_id
+    )
+    .fetch_all(&mut *db)
+    .await
+}
+
+/// Schedules the deletion of the account `grace_sec` from now. Asking again
+/// keeps the original schedule.
+pub async fn schedule_deletion(
+    db: &mut PgConnection,
+    user_id: Uuid,
+    grace_sec: f64,
+) -> Result<Option<DeletionSchedule>, sqlx::Error> {
+    sqlx::query_as!(
+        DeletionSchedule,
+        r#"
+        UPDATE users
+        SET deletion_scheduled_at =
+            coalesce(deletion_scheduled_at, now() + make_interval(secs => $2))
+        WHERE id = $1 AND deleted_at IS NULL
+        RETURNING deletion_scheduled_at AS "scheduled_for!";
+        "#,
+        user_id,
+        grace_sec
+    )
+    .fetch_optional(&mut *db)
+    .await
+}
+
+/// Returns false when no deletion was scheduled.
+pub async fn cancel_deletion(db: &mut PgConnection, user_id: Uuid) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE users
+        SET deletion_scheduled_at = NULL
+        WHERE id = $1 AND deletion_scheduled_at IS NOT NULL;
+        "#,
+        user_id
+    )
+    .execute(&mut *db)
+    .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+/// Accounts whose grace period is over.
+pub async fn get_due_deletions(db: &mut PgConnection) -> Result<Vec<Uuid>, sqlx::Error> {
+    sqlx::query_scalar!(r"SELECT id FROM users WHERE deletion_scheduled_at <= now();")
+        .fetch_all(&mut *db)
+        .await
+}
+
+/// Deletes the account and, through the cascades, everything it owns:
+/// sessions, devices, keys, chats and their messages on both sides. Does
+/// nothing if the deletion was cancelled in the meantime.
+pub async fn delete_account(
+    db: &mut PgConnection,
+    user_id: Uuid,
+) -> Result<Option<DeletedAccount>, sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    if !lock_due_account(&mut tx, user_id).await? {
+        return Ok(None);
+    }
+
+    let chats = sqlx::query!(
+        r#"
+        SELECT chat_id AS "chat_id!", friend_id AS "friend_id!"
+        FROM chat_members
+        WHERE user_id = $1;
+        "#,
+        user_id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let attachment_ids = sqlx::query_scalar!(
+        r#"
+        SELECT a.id
+        FROM attachments a
+        JOIN chat_members cm ON cm.chat_id = a.chat_id
+        WHERE cm.user_id = $1;
+        "#,
+        user_id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    leave_groups(&mut tx, user_id).await?;
+
+    sqlx::query!(r"DELETE FROM users WHERE id = $1;", user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    for c in chats {
+        let notification = Notification::ChatRemoved {
+            chat_id: c.chat_id,
+            user_ids: [user_id, c.friend_id],
+        };
+
+        events::repo::notify(&mut tx, &notification).await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(Some(DeletedAccount {
+        user_id,
+        attachment_ids,
+    }))
+}
+
+/// Strips the account down to a tombstone under a random name, so that the
+/// messages friends already have keep a sender but no longer point back at
+/// who it was. Pending invites go, accepted chats stay readable.
+pub async fn pseudonymize_account(
+    db: &mut PgConnection,
+    user_id: Uuid,
+) -> Result<Option<DeletedAccount>, sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    if !lock_due_account(&mut tx, user_id).await? {
+        return Ok(None);
+    }
+
+    let pending = sqlx::query!(
+        r#"
+        DELETE FROM chats c
+        WHERE (sender_id = $1 OR recipient_id = $1) AND recipient_public_key IS NULL
+        RETURNING
+            id,
+            sender_id,
+            recipient_id,
+            ARRAY(SELECT id FROM attachments WHERE chat_id = c.id) AS "attachment_ids!";
+        "#,
+        user_id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    leave_groups(&mut tx, user_id).await?;
+
+    sqlx::query!(r"DELETE FROM devices WHERE user_id = $1;", user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query!(r"DELETE FROM identity_keys WHERE user_id = $1;", user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query!(
+        r"DELETE FROM blocks WHERE blocker_id = $1 OR blocked_id = $1;",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(r"DELETE FROM totp_secrets WHERE user_id = $1;", user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query!(r"DELETE FROM recovery_codes WHERE user_id = $1;", user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query!(
+        r#"
+        UPDATE users
+        SET
+            username = 'deleted-' || substr(md5(random()::text), 1, 24),
+            password = '',
+            pbkdf2_salt = '',
+            deletion_scheduled_at = NULL,
+            deleted_at = now()
+        WHERE id = $1;
+        "#,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    for c in &pending {
+        let notification = Notification::ChatRemoved {
+            chat_id: c.id,
+            user_ids: [c.sender_id, c.recipient_id],
+        };
+
+        events::repo::notify(&mut tx, &notification).await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(Some(DeletedAccount {
+        user_id,
+        attachment_ids: pending.into_iter().flat_map(|c| c.attachment_ids).collect(),
+    }))
+}
+
+/// Locks the account for the rest of the transaction, telling whether its
+/// deletion is still due.
+async fn lock_due_account(db: &mut PgConnection, user_id: Uuid) -> Result<bool, sqlx::Error> {
+    let due = sqlx::query_scalar!(
+        r#"
+        SELECT id
+        FROM users
+        WHERE id = $1 AND deletion_scheduled_at <= now()
+        FOR UPDATE;
+        "#,
+        user_id
+    )
+    .fetch_optional(&mut *db)
+    .await?;
+
+    Ok(due.is_some())
+}
+
+/// Drops every group membership of the user. Groups left without an owner
+/// pass to their longest standing admin, or member, and empty ones go.
+async fn leave_groups(db: &mut PgConnection, user_id: Uuid) -> Result<(), sqlx::Error> {
+    let group_ids = sqlx::query_scalar!(
+        r"DELETE FROM group_members WHERE user_id = $1 RETURNING group_id;",
+        user_id
+    )
+    .fetch_all(&mut *db)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        UPDATE group_members
+        SET role = 'owner'
+        WHERE (group_id, user_id) IN (
+            SELECT DISTINCT ON (group_id) group_id, user_id
+            FROM group_members m
+            WHERE group_id = ANY($1) AND NOT EXISTS(
+                SELECT 1
+                FROM group_members o
+                WHERE o.group_id = m.group_id AND o.role = 'owner'
+            )
+            ORDER BY group_id, role, public_key IS NULL, created_at
+        );
+        "#,
+        &group_ids
+    )
+    .execute(&mut *db)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        DELETE FROM groups g
+        WHERE id = ANY($1) AND NOT EXISTS(
+            SELECT 1
+            FROM group_members
+            WHERE group_id = g.id
+        );
+        "#,
+        &group_ids
+    )
+    .execute(&mut *db)
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,117 @@
+use crate::support::{client, client_with, User};
+use rocket::{
+    http::Status,
+    local::asynchronous::Client,
+    serde::json::{json, Value},
+    tokio::time,
+};
+use std::time::Duration;
+
+async fn delete(client: &Client, user: &User, password: &str) -> Status {
+    client
+        .delete("/users/me")
+        .header(user.authorization())
+        .json(&json!({ "password": password }))
+        .dispatch()
+        .await
+        .status()
+}
+
+async fn chat_count(client: &Client, user: &User) -> usize {
+    let response = client
+        .get("/chats")
+        .header(user.authorization())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+
+    response.into_json::<Value>().await.unwrap()["chats"]
+        .as_array()
+        .unwrap()
+        .len()
+}
+
+#[rocket::async_test]
+async fn deletion_can_be_cancelled_during_the_grace_period() {
+    let client = client().await;
+    let user = User::sign_up(&client, "owner").await;
+    let friend = User::sign_up(&client, "friend").await;
+    user.befriend(&client, &friend).await;
+
+    assert_eq!(
+        delete(&client, &user, "not-the-password-1!").await,
+        Status::Forbidden
+    );
+    assert_eq!(delete(&client, &user, &user.password).await, Status::Ok);
+
+    let cancel = || {
+        client
+            .delete("/users/me/deletion")
+            .header(user.authorization())
+            .dispatch()
+    };
+    assert_eq!(cancel().await.status(), Status::Ok);
+    assert_eq!(cancel().await.status(), Status::NotFound);
+
+    assert_eq!(user.send(&client, &friend, "00").await, Status::Ok);
+}
+
+#[rocket::async_test]
+async fn deleted_accounts_take_their_chats_along() {
+    let client = client_with(
+        r#"
+        account_deletion_grace_sec = 0
+        purge_interval_sec = 1
+        "#,
+    )
+    .await;
+    let user = User::sign_up(&client, "owner").await;
+    let friend = User::sign_up(&client, "friend").await;
+    user.befriend(&client, &friend).await;
+    assert_eq!(user.send(&client, &friend, "00").await, Status::Ok);
+
+    let response = client
+        .post(format!("/attachments?friend_id={}", friend.id))
+        .header(user.authorization())
+        .body([0; 16])
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let attachment: Value = response.into_json().await.unwrap();
+
+    assert_eq!(chat_count(&client, &friend).await, 1);
+    assert_eq!(delete(&client, &user, &user.password).await, Status::Ok);
+
+    for _ in 0..20 {
+        if chat_count(&client, &friend).await == 0 {
+            break;
+        }
+
+        time::sleep(Duration::from_millis(500)).await;
+    }
+
+    assert_eq!(chat_count(&client, &friend).await, 0);
+    let response = client
+        .get(format!("/users/{}/messages", user.id))
+        .header(friend.authorization())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
+
+    let response = client
+        .get(format!(
+            "/attachments/{}",
+            attachment["id"].as_str().unwrap()
+        ))
+        .header(friend.authorization())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
+
+    let response = client
+        .post("/auth/signin")
+        .json(&json!({ "username": user.username, "password": user.password }))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Unauthorized);
+}
@@ -0,0 +1,91 @@
+use crate::support::{client, User};
+use rocket::{
+    http::{Header, Status},
+    local::asynchronous::Client,
+    serde::json::Value,
+};
+
+async fn upload(client: &Client, user: &User, friend: &User, blob: &[u8]) -> String {
+    let response = client
+        .post(format!("/attachments?friend_id={}", friend.id))
+        .header(user.authorization())
+        .body(blob)
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+
+    response.into_json::<Value>().await.unwrap()["id"]
+        .as_str()
+        .unwrap()
+        .to_string()
+}
+
+#[rocket::async_test]
+async fn downloads_serve_byte_ranges() {
+    let client = client().await;
+    let sender = User::sign_up(&client, "sender").await;
+    let recipient = User::sign_up(&client, "recipient").await;
+    let stranger = User::sign_up(&client, "stranger").await;
+    sender.befriend(&client, &recipient).await;
+
+    let blob: Vec<u8> = (0..=255).collect();
+    let id = upload(&client, &sender, &recipient, &blob).await;
+
+    let response = client
+        .get(format!("/attachments/{}", id))
+        .header(recipient.authorization())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), blob);
+
+    let ranges = [
+        ("bytes=10-19", "bytes 10-19/256", &blob[10..20]),
+        ("bytes=-5", "bytes 251-255/256", &blob[251..]),
+        ("bytes=250-", "bytes 250-255/256", &blob[250..]),
+    ];
+
+    for (range, content_range, expected) in ranges {
+        let response = client
+            .get(format!("/attachments/{}", id))
+            .header(recipient.authorization())
+            .header(Header::new("Range", range))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::PartialContent);
+        assert_eq!(
+            response.headers().get_one("Content-Range"),
+            Some(content_range)
+        );
+        assert_eq!(response.into_bytes().await.unwrap(), expected);
+    }
+
+    let response = client
+        .get(format!("/attachments/{}", id))
+        .header(stranger.authorization())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[rocket::async_test]
+async fn unsatisfiable_ranges_report_the_size() {
+    let client = client().await;
+    let sender = User::sign_up(&client, "sender").await;
+    let recipient = User::sign_up(&client, "recipient").await;
+    sender.befriend(&client, &recipient).await;
+
+    let id = upload(&client, &sender, &recipient, &[0; 100]).await;
+
+    let response = client
+        .get(format!("/attachments/{}", id))
+        .header(recipient.authorization())
+        .header(Header::new("Range", "bytes=500-"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::RangeNotSatisfiable);
+    assert_eq!(
+        response.headers().get_one("Content-Range"),
+        Some("bytes */100")
+    );
+}
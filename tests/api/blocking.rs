@@ -0,0 +1,116 @@
+use crate::support::{client, User};
+use rocket::{http::Status, local::asynchronous::Client, serde::json::Value};
+
+async fn block(client: &Client, user: &User, blocked: &User) {
+    let response = client
+        .post(format!("/users/{}/block", blocked.id))
+        .header(user.authorization())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+}
+
+async fn invite(client: &Client, user: &User, invitee: &User) -> Status {
+    client
+        .post(format!("/users/{}/invite", invitee.id))
+        .header(user.authorization())
+        .json(&user.signed_key())
+        .dispatch()
+        .await
+        .status()
+}
+
+async fn usernames(client: &Client, user: &User, path: &str) -> Vec<String> {
+    let response = client
+        .get(path)
+        .header(user.authorization())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+
+    response
+        .into_json::<Vec<Value>>()
+        .await
+        .unwrap()
+        .iter()
+        .map(|u| u["username"].as_str().unwrap().to_string())
+        .collect()
+}
+
+#[rocket::async_test]
+async fn blocks_stop_messages_both_ways_until_lifted() {
+    let client = client().await;
+    let blocker = User::sign_up(&client, "blocker").await;
+    let blocked = User::sign_up(&client, "blocked").await;
+    blocker.befriend(&client, &blocked).await;
+    block(&client, &blocker, &blocked).await;
+
+    assert_eq!(
+        blocker.send(&client, &blocked, "00").await,
+        Status::Forbidden
+    );
+    assert_eq!(
+        blocked.send(&client, &blocker, "00").await,
+        Status::Forbidden
+    );
+
+    let response = client
+        .delete(format!("/users/{}/block", blocked.id))
+        .header(blocker.authorization())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+
+    assert_eq!(blocker.send(&client, &blocked, "01").await, Status::Ok);
+    assert_eq!(blocked.send(&client, &blocker, "02").await, Status::Ok);
+}
+
+#[rocket::async_test]
+async fn blocks_stop_invites_and_accepts_both_ways() {
+    let client = client().await;
+    let blocker = User::sign_up(&client, "blocker").await;
+    let blocked = User::sign_up(&client, "blocked").await;
+    let inviter = User::sign_up(&client, "inviter").await;
+
+    block(&client, &blocker, &blocked).await;
+    assert_eq!(invite(&client, &blocker, &blocked).await, Status::NotFound);
+    assert_eq!(invite(&client, &blocked, &blocker).await, Status::NotFound);
+
+    assert_eq!(invite(&client, &inviter, &blocker).await, Status::Ok);
+    block(&client, &blocker, &inviter).await;
+
+    let response = client
+        .post(format!("/users/{}/accept", inviter.id))
+        .header(blocker.authorization())
+        .json(&blocker.signed_key())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[rocket::async_test]
+async fn blocked_users_are_listed_and_hidden_from_search() {
+    let client = client().await;
+    let blocker = User::sign_up(&client, "blocker").await;
+    let blocked = User::sign_up(&client, "blocked").await;
+    blocker.befriend(&client, &blocked).await;
+
+    let friends = usernames(&client, &blocked, "/users?filter=friends").await;
+    assert_eq!(friends, [blocker.username.as_str()]);
+
+    block(&client, &blocker, &blocked).await;
+
+    let search = format!("/users?q={}", blocker.username);
+    assert!(usernames(&client, &blocked, &search).await.is_empty());
+
+    let search = format!("/users?q={}", blocked.username);
+    assert!(usernames(&client, &blocker, &search).await.is_empty());
+
+    assert!(usernames(&client, &blocked, "/users?filter=friends")
+        .await
+        .is_empty());
+    assert_eq!(
+        usernames(&client, &blocker, "/users/blocked").await,
+        [blocked.username.as_str()]
+    );
+}
@@ -0,0 +1,40 @@
+use crate::support::{client, User};
+use rocket::http::Status;
+
+#[rocket::async_test]
+async fn both_parties_can_message_after_accept() {
+    let client = client().await;
+    let inviter = User::sign_up(&client, "inviter").await;
+    let invitee = User::sign_up(&client, "invitee").await;
+
+    let response = client
+        .post(format!("/users/{}/invite", invitee.id))
+        .header(inviter.authorization())
+        .json(&inviter.signed_key())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+
+    assert_eq!(
+        inviter.send(&client, &invitee, "00").await,
+        Status::Forbidden
+    );
+    assert_eq!(
+        invitee.send(&client, &inviter, "00").await,
+        Status::Forbidden
+    );
+
+    let response = client
+        .post(format!("/users/{}/accept", inviter.id))
+        .header(invitee.authorization())
+        .json(&invitee.signed_key())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+
+    assert_eq!(inviter.send(&client, &invitee, "01").await, Status::Ok);
+    assert_eq!(invitee.send(&client, &inviter, "02").await, Status::Ok);
+
+    assert_eq!(inviter.history(&client, &invitee).await, ["02", "01"]);
+    assert_eq!(invitee.history(&client, &inviter).await, ["02", "01"]);
+}
@@ -0,0 +1,13 @@
+//! Runs against the database in `DATABASE_URL`, migrated the same way as
+//! for building. One binary, so the helpers in `support` are shared.
+
+mod accounts;
+mod attachments;
+mod blocking;
+mod chat_membership;
+mod messages;
+mod passwords;
+mod prekeys;
+mod sessions;
+mod support;
+mod totp;
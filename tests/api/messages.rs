@@ -0,0 +1,82 @@
+use crate::support::{client, User};
+use rocket::{
+    http::Status,
+    local::asynchronous::Client,
+    serde::json::{json, Value},
+};
+
+async fn send(client: &Client, sender: &User, body: Value) -> (Status, Option<Value>) {
+    let response = client
+        .post("/messages")
+        .header(sender.authorization())
+        .json(&body)
+        .dispatch()
+        .await;
+
+    (response.status(), response.into_json().await)
+}
+
+#[rocket::async_test]
+async fn retried_sends_return_the_original() {
+    let client = client().await;
+    let sender = User::sign_up(&client, "sender").await;
+    let recipient = User::sign_up(&client, "recipient").await;
+    sender.befriend(&client, &recipient).await;
+
+    let body = json!({
+        "recipientId": recipient.id,
+        "content": "00",
+        "clientId": "6d1c5bc4-7f9a-4c52-9d0e-2f4b8e51a7c3",
+        "envelopes": [
+            { "deviceId": sender.device_id, "content": "aa" },
+            { "deviceId": recipient.device_id, "content": "bb" },
+        ],
+    });
+
+    let (status, sent) = send(&client, &sender, body.clone()).await;
+    assert_eq!(status, Status::Ok);
+    let sent = sent.unwrap();
+    assert_eq!(sent["replayed"], false);
+    assert_eq!(sent["content"], "aa");
+
+    let (status, replayed) = send(&client, &sender, body).await;
+    assert_eq!(status, Status::Ok);
+    let replayed = replayed.unwrap();
+    assert_eq!(replayed["replayed"], true);
+    assert_eq!(replayed["id"], sent["id"]);
+    assert_eq!(replayed["content"], "aa");
+
+    assert_eq!(recipient.history(&client, &sender).await, ["bb"]);
+}
+
+#[rocket::async_test]
+async fn reused_client_ids_are_refused() {
+    let client = client().await;
+    let sender = User::sign_up(&client, "sender").await;
+    let recipient = User::sign_up(&client, "recipient").await;
+    let other = User::sign_up(&client, "other").await;
+    sender.befriend(&client, &recipient).await;
+    sender.befriend(&client, &other).await;
+
+    let client_id = "0b6f2a47-3c1e-4d8b-a5f9-71e2c6d4b093";
+    let message = |recipient: &User, content: &str| json!({ "recipientId": recipient.id, "content": content, "clientId": client_id });
+
+    let (status, sent) = send(&client, &sender, message(&recipient, "00")).await;
+    assert_eq!(status, Status::Ok);
+
+    let (status, _) = send(&client, &sender, message(&recipient, "01")).await;
+    assert_eq!(status, Status::Conflict);
+
+    let (status, _) = send(&client, &sender, message(&other, "00")).await;
+    assert_eq!(status, Status::Conflict);
+
+    let response = client
+        .delete(format!("/messages/{}", sent.unwrap()["id"]))
+        .header(sender.authorization())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let (status, _) = send(&client, &sender, message(&recipient, "00")).await;
+    assert_eq!(status, Status::Conflict);
+}
@@ -0,0 +1,142 @@
+use crate::support::{client, totp_code, User};
+use rocket::{
+    http::Status,
+    local::asynchronous::Client,
+    serde::json::{json, Value},
+};
+
+const NEW_PASSWORD: &str = "battery-staple-43?";
+
+async fn sign_in(client: &Client, user: &User, password: &str) -> Status {
+    client
+        .post("/auth/signin")
+        .json(&json!({ "username": user.username, "password": password }))
+        .dispatch()
+        .await
+        .status()
+}
+
+async fn recover(client: &Client, user: &User, recovery_code: &str, code: Option<&str>) -> Status {
+    client
+        .post("/auth/recover")
+        .json(&json!({
+            "username": user.username,
+            "recoveryCode": recovery_code,
+            "code": code,
+            "newPassword": NEW_PASSWORD,
+            "newPasswordCheck": NEW_PASSWORD,
+        }))
+        .dispatch()
+        .await
+        .status()
+}
+
+#[rocket::async_test]
+async fn changing_the_password_revokes_other_sessions() {
+    let client = client().await;
+    let mut phone = User::sign_up(&client, "owner").await;
+    let mut laptop = phone.sign_in(&client).await;
+
+    let change = |password: &str| {
+        json!({
+            "password": password,
+            "newPassword": NEW_PASSWORD,
+            "newPasswordCheck": NEW_PASSWORD,
+        })
+    };
+
+    let response = client
+        .put("/auth/password")
+        .header(phone.authorization())
+        .json(&change("not-the-password-1!"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Forbidden);
+
+    let response = client
+        .put("/auth/password")
+        .header(phone.authorization())
+        .json(&change(&phone.password))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+
+    assert_eq!(laptop.refresh(&client).await, Status::Unauthorized);
+    assert_eq!(phone.refresh(&client).await, Status::Ok);
+    assert_eq!(
+        sign_in(&client, &phone, &phone.password).await,
+        Status::Unauthorized
+    );
+    assert_eq!(sign_in(&client, &phone, NEW_PASSWORD).await, Status::Ok);
+}
+
+#[rocket::async_test]
+async fn recovery_codes_reset_the_password_once() {
+    let client = client().await;
+    let mut user = User::sign_up(&client, "owner").await;
+    let code = user.recovery_codes[0].clone();
+
+    assert_eq!(
+        recover(&client, &user, "aaaa-bbbb-cccc-dddd", None).await,
+        Status::Unauthorized
+    );
+    assert_eq!(recover(&client, &user, &code, None).await, Status::Ok);
+    assert_eq!(
+        recover(&client, &user, &code, None).await,
+        Status::Unauthorized
+    );
+
+    assert_eq!(user.refresh(&client).await, Status::Unauthorized);
+    assert_eq!(sign_in(&client, &user, NEW_PASSWORD).await, Status::Ok);
+}
+
+#[rocket::async_test]
+async fn recovery_takes_a_totp_code_while_enabled() {
+    let client = client().await;
+    let user = User::sign_up(&client, "owner").await;
+    let (secret, recovery_codes) = user.enable_totp(&client).await;
+
+    assert_eq!(
+        recover(&client, &user, &recovery_codes[0], None).await,
+        Status::Unauthorized
+    );
+    assert_eq!(
+        recover(&client, &user, &recovery_codes[0], Some("000000")).await,
+        Status::Unauthorized
+    );
+
+    let code = totp_code(&secret, 0);
+    assert_eq!(
+        recover(&client, &user, &recovery_codes[0], Some(&code)).await,
+        Status::Ok
+    );
+
+    let response = client
+        .post("/auth/signin")
+        .json(&json!({ "username": user.username, "password": NEW_PASSWORD }))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Accepted);
+    assert!(response.into_json::<Value>().await.unwrap()["challenge"].is_string());
+}
+
+#[rocket::async_test]
+async fn wrong_recovery_codes_count_towards_the_totp_lockout() {
+    let client = client().await;
+    let user = User::sign_up(&client, "owner").await;
+    let (secret, recovery_codes) = user.enable_totp(&client).await;
+
+    for _ in 0..5 {
+        let code = totp_code(&secret, 0);
+        assert_eq!(
+            recover(&client, &user, "aaaa-bbbb-cccc-dddd", Some(&code)).await,
+            Status::Unauthorized
+        );
+    }
+
+    let code = totp_code(&secret, 0);
+    assert_eq!(
+        recover(&client, &user, &recovery_codes[0], Some(&code)).await,
+        Status::TooManyRequests
+    );
+}
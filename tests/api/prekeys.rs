@@ -0,0 +1,104 @@
+use crate::support::{client, User};
+use rocket::{
+    http::Status,
+    local::asynchronous::Client,
+    serde::json::{json, Value},
+};
+
+async fn upload_prekeys(client: &Client, user: &User, one_time: i32) {
+    let response = client
+        .put("/keys/signed-prekey")
+        .header(user.authorization())
+        .json(&user.signed_prekey(1))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let prekeys: Vec<Value> = (1..=one_time).map(|id| user.signed_prekey(id)).collect();
+    let response = client
+        .post("/keys/one-time-prekeys")
+        .header(user.authorization())
+        .json(&json!({ "prekeys": prekeys }))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+}
+
+async fn claim(client: &Client, claimer: &User, owner: &User) -> (Status, Option<Value>) {
+    let response = client
+        .post(format!("/keys/{}/bundle", owner.id))
+        .header(claimer.authorization())
+        .dispatch()
+        .await;
+
+    (response.status(), response.into_json().await)
+}
+
+#[rocket::async_test]
+async fn bundles_hand_out_each_one_time_prekey_once() {
+    let client = client().await;
+    let owner = User::sign_up(&client, "owner").await;
+    let first = User::sign_up(&client, "first").await;
+    let second = User::sign_up(&client, "second").await;
+    owner.befriend(&client, &first).await;
+    owner.befriend(&client, &second).await;
+    upload_prekeys(&client, &owner, 1).await;
+
+    let (status, bundle) = claim(&client, &first, &owner).await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(bundle.unwrap()["oneTimePrekey"]["keyId"], 1);
+
+    let (status, bundle) = claim(&client, &second, &owner).await;
+    assert_eq!(status, Status::Ok);
+    let bundle = bundle.unwrap();
+    assert_eq!(bundle["signedPrekey"]["keyId"], 1);
+    assert!(bundle["oneTimePrekey"].is_null());
+
+    let response = client
+        .get("/keys/one-time-prekeys/count")
+        .header(owner.authorization())
+        .dispatch()
+        .await;
+    let count: Value = response.into_json().await.unwrap();
+    assert_eq!(count["remaining"], 0);
+    assert_eq!(count["low"], true);
+}
+
+#[rocket::async_test]
+async fn bundles_are_rate_limited_per_pair() {
+    let client = client().await;
+    let owner = User::sign_up(&client, "owner").await;
+    let first = User::sign_up(&client, "first").await;
+    let second = User::sign_up(&client, "second").await;
+    owner.befriend(&client, &first).await;
+    owner.befriend(&client, &second).await;
+    upload_prekeys(&client, &owner, 3).await;
+
+    assert_eq!(claim(&client, &first, &owner).await.0, Status::Ok);
+    assert_eq!(
+        claim(&client, &first, &owner).await.0,
+        Status::TooManyRequests
+    );
+    assert_eq!(claim(&client, &second, &owner).await.0, Status::Ok);
+}
+
+#[rocket::async_test]
+async fn bundles_are_withheld_from_strangers_and_blocked_users() {
+    let client = client().await;
+    let owner = User::sign_up(&client, "owner").await;
+    let stranger = User::sign_up(&client, "stranger").await;
+    let blocked = User::sign_up(&client, "blocked").await;
+    owner.befriend(&client, &blocked).await;
+    upload_prekeys(&client, &owner, 3).await;
+
+    assert_eq!(claim(&client, &stranger, &owner).await.0, Status::NotFound);
+
+    let response = client
+        .post(format!("/users/{}/block", blocked.id))
+        .header(owner.authorization())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+
+    assert_eq!(claim(&client, &blocked, &owner).await.0, Status::NotFound);
+}
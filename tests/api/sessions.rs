@@ -0,0 +1,60 @@
+use crate::support::{client, User};
+use rocket::{http::Status, local::asynchronous::Client, serde::json::Value};
+
+async fn sessions(client: &Client, user: &User) -> Vec<Value> {
+    let response = client
+        .get("/auth/sessions")
+        .header(user.authorization())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+
+    response.into_json().await.unwrap()
+}
+
+#[rocket::async_test]
+async fn revoked_sessions_can_no_longer_refresh() {
+    let client = client().await;
+    let mut phone = User::sign_up(&client, "owner").await;
+    let mut laptop = phone.sign_in(&client).await;
+
+    let listed = sessions(&client, &phone).await;
+    assert_eq!(listed.len(), 2);
+
+    let other = listed
+        .iter()
+        .find(|s| s["deviceId"] == laptop.device_id.as_str())
+        .unwrap();
+    assert_eq!(other["current"], false);
+
+    let response = client
+        .delete(format!("/auth/sessions/{}", other["id"]))
+        .header(phone.authorization())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+
+    assert_eq!(laptop.refresh(&client).await, Status::Unauthorized);
+    assert_eq!(phone.refresh(&client).await, Status::Ok);
+    assert_eq!(sessions(&client, &phone).await.len(), 1);
+}
+
+#[rocket::async_test]
+async fn logging_out_elsewhere_keeps_the_current_session() {
+    let client = client().await;
+    let mut phone = User::sign_up(&client, "owner").await;
+    let mut laptop = phone.sign_in(&client).await;
+    let mut tablet = phone.sign_in(&client).await;
+
+    let response = client
+        .delete("/auth/sessions")
+        .header(phone.authorization())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_json::<Vec<Value>>().await.unwrap().len(), 2);
+
+    assert_eq!(laptop.refresh(&client).await, Status::Unauthorized);
+    assert_eq!(tablet.refresh(&client).await, Status::Unauthorized);
+    assert_eq!(phone.refresh(&client).await, Status::Ok);
+}
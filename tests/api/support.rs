@@ -0,0 +1,260 @@
+use data_encoding::BASE32_NOPAD;
+use ed25519_dalek::{Signer, SigningKey};
+use hmac::{Hmac, Mac};
+use rocket::{
+    figment::providers::{Format, Toml},
+    http::{Cookie, Header, Status},
+    local::asynchronous::Client,
+    serde::json::{json, Value},
+};
+use sha1::Sha1;
+
+const APP_CONFIG: &str = r#"
+argon_secret = "integration-argon-secret"
+access_token_secret = "integration-access-secret"
+refresh_token_secret = "integration-refresh-secret"
+refresh_token_ttl_sec = 600
+access_token_ttl_sec = 600
+prekey_low_threshold = 10
+prekey_claim_interval_sec = 60
+attachments_dir = "target/integration-attachments"
+attachment_size_limit = 1024
+purge_interval_sec = 60
+attachment_unreferenced_ttl_sec = 86400
+account_deletion_grace_sec = 600
+pseudonymize_deleted_messages = false
+"#;
+
+pub async fn client() -> Client {
+    client_with("").await
+}
+
+/// A client whose config has `overrides` on top of the usual one.
+pub async fn client_with(overrides: &str) -> Client {
+    let url = std::env::var("DATABASE_URL").expect("DATABASE_URL is set");
+    let figment = rocket::Config::figment()
+        .merge(Toml::string(APP_CONFIG))
+        .merge(Toml::string(overrides))
+        .merge(("databases.nanochat.url", url));
+
+    Client::untracked(nanochat::build(figment))
+        .await
+        .expect("valid rocket instance")
+}
+
+/// The TOTP code of a base32 `secret`, `offset` time steps away from now.
+/// Each step is only accepted once, so tests move forward through them.
+pub fn totp_code(secret: &str, offset: i64) -> String {
+    let secret = BASE32_NOPAD.decode(secret.as_bytes()).unwrap();
+    let step = (chrono::Utc::now().timestamp() / 30 + offset) as u64;
+
+    let mut mac = Hmac::<Sha1>::new_from_slice(&secret).unwrap();
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let truncated = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap());
+
+    format!("{:06}", (truncated & 0x7fff_ffff) % 1_000_000)
+}
+
+/// An account as seen from one signed-in device.
+#[derive(Clone)]
+pub struct User {
+    pub id: String,
+    pub username: String,
+    pub password: String,
+    pub recovery_codes: Vec<String>,
+    pub device_id: String,
+    token: String,
+    session: Option<Cookie<'static>>,
+    identity_key: SigningKey,
+}
+
+impl User {
+    pub async fn sign_up(client: &Client, prefix: &str) -> Self {
+        let suffix: String = (0..12)
+            .map(|_| (b'a' + rand::random::<u8>() % 26) as char)
+            .collect();
+        let username = format!("{}-{}", prefix, suffix);
+        let password = "correct-horse-42!".to_string();
+
+        let response = client
+            .post("/auth/signup")
+            .json(&json!({
+                "username": username,
+                "password": password,
+                "passwordCheck": password,
+            }))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let signed_up: Value = response.into_json().await.unwrap();
+
+        let mut user = User {
+            id: signed_up["id"].as_str().unwrap().to_string(),
+            username,
+            password,
+            recovery_codes: strings(&signed_up["recoveryCodes"]),
+            device_id: String::new(),
+            token: String::new(),
+            session: None,
+            identity_key: SigningKey::from_bytes(&rand::random()),
+        };
+        user.start_session(client).await;
+
+        let response = client
+            .put("/keys/identity")
+            .header(user.authorization())
+            .json(&json!({
+                "publicKey": hex::encode(user.identity_key.verifying_key().as_bytes()),
+            }))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        user
+    }
+
+    /// Signs in again as a new device, with a session of its own.
+    pub async fn sign_in(&self, client: &Client) -> User {
+        let mut device = self.clone();
+        device.start_session(client).await;
+
+        device
+    }
+
+    async fn start_session(&mut self, client: &Client) {
+        let response = client
+            .post("/auth/signin")
+            .json(&json!({ "username": self.username, "password": self.password }))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        self.session = response.cookies().get("session").cloned();
+        let access_token: Value = response.into_json().await.unwrap();
+        self.token = access_token["token"].as_str().unwrap().to_string();
+        self.device_id = access_token["deviceId"].as_str().unwrap().to_string();
+    }
+
+    /// Enrolls in 2FA and confirms it with the code of the previous step,
+    /// returning the secret and the recovery codes issued with it.
+    pub async fn enable_totp(&self, client: &Client) -> (String, Vec<String>) {
+        let response = client
+            .post("/auth/totp")
+            .header(self.authorization())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let secret = response.into_json::<Value>().await.unwrap()["secret"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let response = client
+            .post("/auth/totp/confirm")
+            .header(self.authorization())
+            .json(&json!({ "code": totp_code(&secret, -1) }))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let confirmed: Value = response.into_json().await.unwrap();
+
+        (secret, strings(&confirmed["recoveryCodes"]))
+    }
+
+    /// Trades the session cookie for a new access token, keeping the cookie
+    /// it is rotated to.
+    pub async fn refresh(&mut self, client: &Client) -> Status {
+        let mut request = client.post("/auth/refresh").header(self.authorization());
+
+        if let Some(session) = &self.session {
+            request = request.cookie(session.clone());
+        }
+
+        let response = request.dispatch().await;
+
+        if let Some(session) = response.cookies().get("session") {
+            self.session = Some(session.clone());
+        }
+
+        response.status()
+    }
+
+    pub fn authorization(&self) -> Header<'static> {
+        Header::new("Authorization", format!("Bearer {}", self.token))
+    }
+
+    /// A chat public key signed by the identity key.
+    pub fn signed_key(&self) -> Value {
+        let public_key: [u8; 32] = rand::random();
+
+        json!({
+            "publicKey": hex::encode(public_key),
+            "signature": hex::encode(self.identity_key.sign(&public_key).to_bytes()),
+        })
+    }
+
+    /// A prekey under `key_id`, signed the same way.
+    pub fn signed_prekey(&self, key_id: i32) -> Value {
+        let mut key = self.signed_key();
+        key["keyId"] = json!(key_id);
+
+        key
+    }
+
+    /// Invites `friend`, who accepts.
+    pub async fn befriend(&self, client: &Client, friend: &User) {
+        let response = client
+            .post(format!("/users/{}/invite", friend.id))
+            .header(self.authorization())
+            .json(&self.signed_key())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = client
+            .post(format!("/users/{}/accept", self.id))
+            .header(friend.authorization())
+            .json(&friend.signed_key())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    pub async fn send(&self, client: &Client, recipient: &User, content: &str) -> Status {
+        client
+            .post("/messages")
+            .header(self.authorization())
+            .json(&json!({ "recipientId": recipient.id, "content": content }))
+            .dispatch()
+            .await
+            .status()
+    }
+
+    pub async fn history(&self, client: &Client, friend: &User) -> Vec<String> {
+        let response = client
+            .get(format!("/users/{}/messages", friend.id))
+            .header(self.authorization())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        response.into_json::<Value>().await.unwrap()["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["content"].as_str().unwrap().to_string())
+            .collect()
+    }
+}
+
+fn strings(array: &Value) -> Vec<String> {
+    array
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|s| s.as_str().unwrap().to_string())
+        .collect()
+}
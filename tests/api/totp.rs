@@ -0,0 +1,112 @@
+use crate::support::{client, totp_code, User};
+use rocket::{
+    http::Status,
+    local::asynchronous::Client,
+    serde::json::{json, Value},
+};
+
+/// Signs in with the password, returning the challenge handed out in place
+/// of a token.
+async fn challenge(client: &Client, user: &User) -> String {
+    let response = client
+        .post("/auth/signin")
+        .json(&json!({ "username": user.username, "password": user.password }))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Accepted);
+
+    response.into_json::<Value>().await.unwrap()["challenge"]
+        .as_str()
+        .unwrap()
+        .to_string()
+}
+
+async fn answer(client: &Client, challenge: &str, code: &str) -> Status {
+    client
+        .post("/auth/signin/second-factor")
+        .json(&json!({ "challenge": challenge, "code": code }))
+        .dispatch()
+        .await
+        .status()
+}
+
+async fn disable(client: &Client, user: &User, code: &str) -> Status {
+    client
+        .delete("/auth/totp")
+        .header(user.authorization())
+        .json(&json!({ "code": code }))
+        .dispatch()
+        .await
+        .status()
+}
+
+#[rocket::async_test]
+async fn signin_takes_a_second_factor_once_enabled() {
+    let client = client().await;
+    let user = User::sign_up(&client, "user").await;
+    let (secret, recovery_codes) = user.enable_totp(&client).await;
+
+    let first = challenge(&client, &user).await;
+    assert_eq!(
+        answer(&client, &first, "000000").await,
+        Status::Unauthorized
+    );
+    let code = totp_code(&secret, 0);
+    assert_eq!(answer(&client, &first, &code).await, Status::Ok);
+    assert_eq!(answer(&client, &first, &code).await, Status::Unauthorized);
+
+    let second = challenge(&client, &user).await;
+    assert_eq!(answer(&client, &second, &code).await, Status::Unauthorized);
+    assert_eq!(
+        answer(&client, &second, &recovery_codes[0]).await,
+        Status::Ok
+    );
+
+    let third = challenge(&client, &user).await;
+    assert_eq!(
+        answer(&client, &third, &recovery_codes[0]).await,
+        Status::Unauthorized
+    );
+}
+
+#[rocket::async_test]
+async fn repeated_wrong_codes_lock_the_second_factor() {
+    let client = client().await;
+    let user = User::sign_up(&client, "user").await;
+    let (secret, _) = user.enable_totp(&client).await;
+
+    for _ in 0..5 {
+        assert_eq!(disable(&client, &user, "000000").await, Status::Forbidden);
+    }
+
+    let code = totp_code(&secret, 0);
+    assert_eq!(
+        disable(&client, &user, &code).await,
+        Status::TooManyRequests
+    );
+
+    let challenge = challenge(&client, &user).await;
+    assert_eq!(
+        answer(&client, &challenge, &code).await,
+        Status::TooManyRequests
+    );
+}
+
+#[rocket::async_test]
+async fn disabling_takes_an_authenticator_code() {
+    let client = client().await;
+    let user = User::sign_up(&client, "user").await;
+    let (secret, recovery_codes) = user.enable_totp(&client).await;
+
+    assert_eq!(
+        disable(&client, &user, &recovery_codes[0]).await,
+        Status::Forbidden
+    );
+    assert_eq!(
+        disable(&client, &user, &totp_code(&secret, 0)).await,
+        Status::Ok
+    );
+
+    // Signs straight in again, with no challenge.
+    user.sign_in(&client).await;
+}